@@ -1,18 +1,41 @@
-use anyhow::{Result as AResult, bail};
+use anyhow::{Result as AResult, anyhow, bail};
 use aoc_2020::parse_input;
-use std::collections::HashMap;
-
-fn part1(wall_to_device: &Vec<i32>) -> AResult<i32> {
-    let mut counts = HashMap::new();
-    for (l, r) in wall_to_device.iter().zip(wall_to_device.iter().skip(1)) {
-        let diff = r - l;
-        if diff < 1 || diff > 3 {
-            bail!("Invalid chained diff {}", diff);
-        }
-        let count = counts.entry(r - l).or_insert(0);
-        *count += 1;
+use num_bigint::{BigUint, RandBigInt};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::Range;
+
+/// How adapters may be chained, and where the chain starts and ends
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct ChainSpec {
+    /// Smallest joltage increase one adapter can take; must be positive
+    min_step: i32,
+    /// Largest joltage increase one adapter can take
+    max_step: i32,
+    /// Joltage of the outlet the chain starts from; no adapter may be below it
+    wall: i32,
+    /// How far above the highest adapter the device's built-in adapter sits; must be positive
+    device_offset: i32,
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        ChainSpec { min_step: 1, max_step: 3, wall: 0, device_offset: 3 }
+    }
+}
+
+/// Joltage differences are taken in i64 so that extreme walls and steps can't overflow
+fn step(from: i32, to: i32) -> i64 {
+    i64::from(to) - i64::from(from)
+}
+
+impl ChainSpec {
+    fn accepts(&self, from: i32, to: i32) -> bool {
+        let diff = step(from, to);
+        i64::from(self.min_step) <= diff && diff <= i64::from(self.max_step)
     }
-    Ok(counts.get(&1).unwrap_or(&0) * counts.get(&3).unwrap_or(&0))
 }
 
 /*
@@ -39,44 +62,427 @@ feed into this one?).  Then multiply those counts together.
 
 ^^ this one ^^
 
-todo: duplicate joltage adapters; does this work?
-nb: no dupes in input
+Duplicate adapters are distinct items, so counts are kept per position in the chain rather than
+per joltage.
 
  */
 
-fn part2(wall_to_device: &Vec<i32>) -> u64 {
-    let mut adapter_path_count : HashMap<i32, u64> = HashMap::new();
-    // seed the fact there's "one" possible combination to get to 0 joltage
-    adapter_path_count.insert(0, 1);
-
-    //assumes sorted, skips known "wall" path count
-    for adapter in wall_to_device.iter().skip(1) {
-        let mut incoming_counts = 0;
-        for candidate in (adapter - 3) .. *adapter {
-            let candidate_paths = adapter_path_count.get(&candidate).unwrap_or(&0);
-            incoming_counts +=  candidate_paths;
+/// The wall, every adapter in joltage order, and the device
+struct AdapterChain {
+    spec: ChainSpec,
+    /// Sorted; the wall first and the device last
+    joltages: Vec<i32>,
+}
+
+impl AdapterChain {
+    fn new(mut adapters: Vec<i32>, spec: ChainSpec) -> AResult<AdapterChain> {
+        if spec.min_step < 1 || spec.min_step > spec.max_step {
+            bail!("Invalid step range {}..={}", spec.min_step, spec.max_step);
+        }
+        // the device has to sit above every adapter to keep `joltages` sorted
+        if spec.device_offset < 1 {
+            bail!("Invalid device offset {}; the device must be above every adapter", spec.device_offset);
+        }
+        adapters.sort_unstable();
+        if let Some(lowest) = adapters.first().filter(|lowest| **lowest < spec.wall) {
+            bail!("Adapter {} is below the wall joltage {}", lowest, spec.wall);
+        }
+        let highest = adapters.last().copied().unwrap_or(spec.wall);
+        let device = highest.checked_add(spec.device_offset).ok_or_else(|| anyhow!("Device joltage overflows"))?;
+        let mut joltages = Vec::with_capacity(adapters.len() + 2);
+        joltages.push(spec.wall);
+        joltages.extend(adapters);
+        joltages.push(device);
+        Ok(AdapterChain { spec, joltages })
+    }
+
+    fn wall(&self) -> i32 {
+        self.joltages[0]
+    }
+
+    fn device(&self) -> i32 {
+        *self.joltages.last().unwrap()
+    }
+
+    fn adapters(&self) -> &[i32] {
+        &self.joltages[1 .. self.joltages.len() - 1]
+    }
+
+    /// How often each joltage difference occurs when every adapter is used.  Fails if some
+    /// difference is outside the allowed steps, since then no such chain exists.
+    fn diff_histogram(&self) -> AResult<BTreeMap<i64, usize>> {
+        let mut counts = BTreeMap::new();
+        for (l, r) in self.joltages.iter().zip(self.joltages.iter().skip(1)) {
+            if !self.spec.accepts(*l, *r) {
+                bail!("Invalid chained diff {} between {} and {}", step(*l, *r), l, r);
+            }
+            *counts.entry(step(*l, *r)).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    /// Positions in `joltages` that can directly follow position `idx`
+    fn successors(&self, idx: usize) -> Range<usize> {
+        let joltage = self.joltages[idx];
+        let start = self.joltages.partition_point(|j| step(joltage, *j) < i64::from(self.spec.min_step)).max(idx + 1);
+        let end = self.joltages.partition_point(|j| step(joltage, *j) <= i64::from(self.spec.max_step)).max(start);
+        start .. end
+    }
+
+    /// For every position, the number of ways to continue from it to the device.  Identical
+    /// adapters count as different items.
+    fn ways_to_device<C: ArrangementCount>(&self) -> AResult<Vec<C>> {
+        let len = self.joltages.len();
+        let mut ways = vec![C::zero(); len];
+        ways[len - 1] = C::one();
+        for idx in (0 .. len - 1).rev() {
+            let mut total = C::zero();
+            for next in self.successors(idx) {
+                total = total.plus(&ways[next]).ok_or_else(|| anyhow!("Arrangement count doesn't fit in {}", C::NAME))?;
+            }
+            ways[idx] = total;
+        }
+        Ok(ways)
+    }
+
+    /// Number of ways to get from the wall to the device
+    fn arrangement_count<C: ArrangementCount>(&self) -> AResult<C> {
+        Ok(self.ways_to_device::<C>()?.swap_remove(0))
+    }
+}
+
+/// Number types arrangements can be counted in
+trait ArrangementCount: Clone + Display {
+    const NAME: &'static str;
+    fn zero() -> Self;
+    fn one() -> Self;
+    /// `None` if the sum doesn't fit
+    fn plus(&self, other: &Self) -> Option<Self>;
+}
+
+impl ArrangementCount for u64 {
+    const NAME: &'static str = "u64";
+    fn zero() -> Self { 0 }
+    fn one() -> Self { 1 }
+    fn plus(&self, other: &Self) -> Option<Self> { self.checked_add(*other) }
+}
+
+impl ArrangementCount for u128 {
+    const NAME: &'static str = "u128";
+    fn zero() -> Self { 0 }
+    fn one() -> Self { 1 }
+    fn plus(&self, other: &Self) -> Option<Self> { self.checked_add(*other) }
+}
+
+impl ArrangementCount for BigUint {
+    const NAME: &'static str = "BigUint";
+    fn zero() -> Self { BigUint::from(0u8) }
+    fn one() -> Self { BigUint::from(1u8) }
+    fn plus(&self, other: &Self) -> Option<Self> { Some(self + other) }
+}
+
+/// Every valid arrangement of a chain, in lexicographic order of joltages.  An arrangement is
+/// the list of positions in `AdapterChain::joltages` it uses, wall and device included, so
+/// identical adapters give different arrangements.
+struct Arrangements<'a> {
+    chain: &'a AdapterChain,
+    /// Ways to reach the device from each position
+    ways: Vec<BigUint>,
+}
+
+impl<'a> Arrangements<'a> {
+    fn new(chain: &'a AdapterChain) -> Arrangements<'a> {
+        let ways = chain.ways_to_device::<BigUint>().expect("BigUint counts can't overflow");
+        Arrangements { chain, ways }
+    }
+
+    fn count(&self) -> &BigUint {
+        &self.ways[0]
+    }
+
+    /// Lazily walks the arrangements.  Dead ends are never entered, so each arrangement costs at
+    /// most one step per position it uses.
+    fn iter(&self) -> ArrangementIter<'_, 'a> {
+        let done = self.ways[0] == BigUint::from(0u8);
+        ArrangementIter { arrangements: self, path: vec![0], pending: vec![self.chain.successors(0)], done }
+    }
+
+    /// The `rank`-th arrangement (counting from 0) in `iter` order, found without enumerating
+    /// the ones before it
+    fn nth(&self, rank: &BigUint) -> Option<Vec<usize>> {
+        if rank >= self.count() {
+            return None;
         }
-        adapter_path_count.insert(*adapter, incoming_counts);
+        let mut rank = rank.clone();
+        let mut path = vec![0];
+        let last = self.ways.len() - 1;
+        while *path.last().unwrap() != last {
+            let idx = *path.last().unwrap();
+            for next in self.chain.successors(idx) {
+                if rank < self.ways[next] {
+                    path.push(next);
+                    break;
+                }
+                rank -= &self.ways[next];
+            }
+        }
+        Some(path)
+    }
+
+    /// An arrangement drawn uniformly at random, or `None` if there are none
+    fn sample<R: Rng>(&self, rng: &mut R) -> Option<Vec<usize>> {
+        if *self.count() == BigUint::from(0u8) {
+            return None;
+        }
+        self.nth(&rng.gen_biguint_below(self.count()))
     }
 
-    let device_joltage = wall_to_device.last().unwrap();
-    let paths_to_device = adapter_path_count.get(device_joltage).unwrap();
-    *paths_to_device
+    fn joltages(&self, arrangement: &[usize]) -> Vec<i32> {
+        arrangement.iter().map(|idx| self.chain.joltages[*idx]).collect()
+    }
+}
+
+struct ArrangementIter<'r, 'a> {
+    arrangements: &'r Arrangements<'a>,
+    path: Vec<usize>,
+    /// Per entry of `path`, the successors not tried yet
+    pending: Vec<Range<usize>>,
+    done: bool,
+}
+
+impl Iterator for ArrangementIter<'_, '_> {
+    type Item = Vec<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let last = self.arrangements.ways.len() - 1;
+        let zero = BigUint::from(0u8);
+        while let Some(candidates) = self.pending.last_mut() {
+            match candidates.next() {
+                Some(next) if next == last => {
+                    let mut arrangement = self.path.clone();
+                    arrangement.push(next);
+                    return Some(arrangement);
+                },
+                Some(next) if self.arrangements.ways[next] != zero => {
+                    self.path.push(next);
+                    self.pending.push(self.arrangements.chain.successors(next));
+                },
+                Some(_) => {},
+                None => {
+                    self.path.pop();
+                    self.pending.pop();
+                },
+            }
+        }
+        self.done = true;
+        None
+    }
 }
 
+/// Lists, ranks or samples arrangements, one per line as joltages
+fn explore(chain: &AdapterChain, command: &[String]) -> AResult<()> {
+    let arrangements = Arrangements::new(chain);
+    let print = |arrangement: &[usize]| println!("{}", arrangements.joltages(arrangement).iter().map(|j| j.to_string()).collect::<Vec<_>>().join(" "));
+    match command {
+        [cmd] if cmd == "list" => arrangements.iter().for_each(|arrangement| print(&arrangement)),
+        [cmd, limit] if cmd == "list" => arrangements.iter().take(limit.parse()?).for_each(|arrangement| print(&arrangement)),
+        [cmd, rank] if cmd == "nth" => {
+            let rank: BigUint = rank.parse()?;
+            let arrangement = arrangements.nth(&rank)
+                .ok_or_else(|| anyhow!("Rank {} is out of range; there are {} arrangements", rank, arrangements.count()))?;
+            print(&arrangement);
+        },
+        [cmd, rest @ ..] if cmd == "sample" && rest.len() <= 2 => {
+            let samples = rest.first().map(|n| n.parse()).transpose()?.unwrap_or(1);
+            let mut rng = match rest.get(1) {
+                Some(seed) => StdRng::seed_from_u64(seed.parse()?),
+                None => StdRng::from_entropy(),
+            };
+            for _ in 0 .. samples {
+                print(&arrangements.sample(&mut rng).ok_or_else(|| anyhow!("There are no valid arrangements"))?);
+            }
+        },
+        _ => bail!(USAGE),
+    }
+    Ok(())
+}
+
+const USAGE: &str = "Usage: d10p1 [--input FILE] [--min-step N] [--max-step N] [--wall N] [--device-offset N] [--count u64|u128|big]
+             [list [LIMIT] | nth K | sample [N [SEED]]]";
+
 fn main() -> AResult<()> {
-    let mut adapters : Vec<i32> = parse_input(10, |line| line.parse())?;
-    adapters.sort();
-    let adapter_count = adapters.len();
-    let mut wall_to_device = vec![0];
-    let device_joltage = *adapters.last().unwrap() + 3;
-    wall_to_device.extend(adapters.iter());
-    wall_to_device.push(device_joltage);
-    println!("Wall joltage: 0, Device joltage: {}, Adapters: {}", device_joltage, adapter_count);
+    let mut args = std::env::args().skip(1).peekable();
+    let mut input_path = None;
+    let mut spec = ChainSpec::default();
+    let mut count_type = "u128".to_string();
+    while let Some(flag) = args.next_if(|arg| arg.starts_with("--")) {
+        let value = args.next().ok_or_else(|| anyhow!(USAGE))?;
+        match flag.as_str() {
+            "--input" => input_path = Some(value),
+            "--min-step" => spec.min_step = value.parse()?,
+            "--max-step" => spec.max_step = value.parse()?,
+            "--wall" => spec.wall = value.parse()?,
+            "--device-offset" => spec.device_offset = value.parse()?,
+            "--count" => count_type = value,
+            _ => bail!(USAGE),
+        }
+    }
+    let adapters: Vec<i32> = match input_path {
+        Some(path) => std::fs::read_to_string(path)?.lines().map(|line| line.trim().parse()).collect::<Result<_, _>>()?,
+        None => parse_input(10, |line| line.parse())?,
+    };
+
+    let chain = AdapterChain::new(adapters, spec)?;
+    let command: Vec<String> = args.collect();
+    if !command.is_empty() {
+        return explore(&chain, &command);
+    }
+    println!("Wall joltage: {}, Device joltage: {}, Adapters: {}", chain.wall(), chain.device(), chain.adapters().len());
 
-    println!("Part 1: {}", part1(&wall_to_device)?);
+    // with duplicate adapters or unusual steps there may be no chain using every adapter
+    match chain.diff_histogram() {
+        Ok(histogram) => {
+            for (diff, count) in histogram.iter() {
+                println!("Diff {}: {}", diff, count);
+            }
+            println!("Part 1: {}", histogram.get(&1).unwrap_or(&0) * histogram.get(&3).unwrap_or(&0));
+        },
+        Err(err) => println!("Part 1: {}", err),
+    }
 
-    println!("Part 2: {}", part2(&wall_to_device));
+    let count = match count_type.as_str() {
+        "u64" => chain.arrangement_count::<u64>()?.to_string(),
+        "u128" => chain.arrangement_count::<u128>()?.to_string(),
+        "big" => chain.arrangement_count::<BigUint>()?.to_string(),
+        _ => bail!(USAGE),
+    };
+    println!("Part 2: {}", count);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts arrangements by trying every subset of adapters
+    fn brute_force_count(chain: &AdapterChain) -> u64 {
+        let adapters = chain.adapters();
+        (0u64 .. 1 << adapters.len()).filter(|mask| {
+            let mut joltages = vec![chain.wall()];
+            joltages.extend(adapters.iter().enumerate().filter(|(idx, _)| mask & (1 << idx) != 0).map(|(_, j)| *j));
+            joltages.push(chain.device());
+            joltages.windows(2).all(|pair| chain.spec.accepts(pair[0], pair[1]))
+        }).count() as u64
+    }
+
+    fn count(adapters: &[i32]) -> u64 {
+        AdapterChain::new(adapters.to_vec(), ChainSpec::default()).unwrap().arrangement_count().unwrap()
+    }
+
+    #[test]
+    fn puzzle_examples() {
+        assert_eq!(count(&[16, 10, 15, 5, 1, 11, 7, 19, 6, 12, 4]), 8);
+        assert_eq!(count(&[28, 33, 18, 42, 31, 14, 46, 20, 48, 47, 24, 23, 49, 45, 19, 38, 39, 11, 1, 32, 25, 35, 8, 17, 7, 9, 4, 2, 34, 10, 3]), 19208);
+    }
+
+    #[test]
+    fn duplicate_adapters_are_distinct() {
+        // either copy of 1 can be used, but not both
+        assert_eq!(count(&[1, 1]), 2);
+        // device at 5: 2 alone, or either 1 followed by 2
+        assert_eq!(count(&[1, 1, 2]), 3);
+        assert_eq!(count(&[3, 3, 3]), 3);
+        assert_eq!(count(&[1, 2, 2, 3]), brute_force_count(&AdapterChain::new(vec![1, 2, 2, 3], ChainSpec::default()).unwrap()));
+    }
+
+    #[test]
+    fn count_types_report_overflow() {
+        let spec = ChainSpec { max_step: 9, ..ChainSpec::default() };
+        let chain = AdapterChain::new((1 ..= 120).collect(), spec).unwrap();
+        assert!(chain.arrangement_count::<u64>().is_err());
+        let wide: u128 = chain.arrangement_count().unwrap();
+        let big: BigUint = chain.arrangement_count().unwrap();
+        assert_eq!(big, BigUint::from(wide));
+    }
+
+    #[test]
+    fn extreme_specs_do_not_overflow() {
+        let spec = ChainSpec { max_step: i32::MAX, ..ChainSpec::default() };
+        let chain = AdapterChain::new(vec![1, 5, i32::MAX - 3], spec).unwrap();
+        assert_eq!(chain.arrangement_count::<u64>().unwrap(), 8);
+        assert_eq!(chain.diff_histogram().unwrap().values().sum::<usize>(), 4);
+
+        let spec = ChainSpec { wall: i32::MIN, max_step: i32::MAX, ..ChainSpec::default() };
+        let chain = AdapterChain::new(vec![-1, 10], spec).unwrap();
+        assert_eq!(chain.arrangement_count::<u64>().unwrap(), 2);
+        assert_eq!(chain.diff_histogram().unwrap().keys().copied().collect::<Vec<_>>(), vec![3, 11, i64::from(i32::MAX)]);
+    }
+
+    /// A small random spec and adapter list; few distinct joltages, so most lists repeat some
+    fn random_chain(rng: &mut StdRng) -> (Vec<i32>, ChainSpec) {
+        let min_step = rng.gen_range(1 ..= 2);
+        let spec = ChainSpec {
+            min_step,
+            max_step: rng.gen_range(min_step ..= 4),
+            wall: rng.gen_range(-2 ..= 1),
+            device_offset: rng.gen_range(1 ..= 4),
+        };
+        let top = rng.gen_range(1 ..= 12);
+        let adapters = (0 .. rng.gen_range(0 ..= 10)).map(|_| rng.gen_range(1 ..= top)).collect();
+        (adapters, spec)
+    }
+
+    #[test]
+    fn random_duplicates_match_brute_force() {
+        let mut rng = StdRng::seed_from_u64(2020);
+        for _ in 0 .. 2_000 {
+            let (adapters, spec) = random_chain(&mut rng);
+            let chain = AdapterChain::new(adapters.clone(), spec).unwrap();
+            let expected = brute_force_count(&chain);
+            let small: u64 = chain.arrangement_count().unwrap();
+            let wide: u128 = chain.arrangement_count().unwrap();
+            let big: BigUint = chain.arrangement_count().unwrap();
+            assert_eq!(small, expected, "{:?} with {:?}", adapters, spec);
+            assert_eq!(wide, u128::from(expected), "{:?} with {:?}", adapters, spec);
+            assert_eq!(big, BigUint::from(expected), "{:?} with {:?}", adapters, spec);
+        }
+    }
+
+    #[test]
+    fn lists_puzzle_example_in_order() {
+        let chain = AdapterChain::new(vec![16, 10, 15, 5, 1, 11, 7, 19, 6, 12, 4], ChainSpec::default()).unwrap();
+        let arrangements = Arrangements::new(&chain);
+        let listed: Vec<Vec<i32>> = arrangements.iter().map(|arrangement| arrangements.joltages(&arrangement)).collect();
+        assert_eq!(listed.len(), 8);
+        assert_eq!(listed[0], vec![0, 1, 4, 5, 6, 7, 10, 11, 12, 15, 16, 19, 22]);
+        assert_eq!(listed[7], vec![0, 1, 4, 7, 10, 12, 15, 16, 19, 22]);
+    }
+
+    #[test]
+    fn enumeration_ranking_and_sampling_agree() {
+        let mut rng = StdRng::seed_from_u64(2020);
+        for _ in 0 .. 1_000 {
+            let (adapters, spec) = random_chain(&mut rng);
+            let chain = AdapterChain::new(adapters.clone(), spec).unwrap();
+            let arrangements = Arrangements::new(&chain);
+            let listed: Vec<Vec<usize>> = arrangements.iter().collect();
+            assert_eq!(listed.len() as u64, brute_force_count(&chain), "{:?} with {:?}", adapters, spec);
+            for (rank, arrangement) in listed.iter().enumerate() {
+                let joltages = arrangements.joltages(arrangement);
+                assert!(joltages.windows(2).all(|pair| spec.accepts(pair[0], pair[1])), "invalid arrangement {:?}", joltages);
+                if rank > 0 {
+                    assert!(listed[rank - 1] < *arrangement, "arrangements {} and {} are out of order", rank - 1, rank);
+                }
+                assert_eq!(arrangements.nth(&BigUint::from(rank)).as_ref(), Some(arrangement));
+            }
+            assert_eq!(arrangements.nth(&BigUint::from(listed.len())), None);
+            if let Some(sampled) = arrangements.sample(&mut rng) {
+                assert!(listed.contains(&sampled), "sampled an unlisted arrangement {:?}", sampled);
+            }
+        }
+    }
+}
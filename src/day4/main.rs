@@ -2,6 +2,8 @@ use aoc_2020::read_input;
 use anyhow::{bail, Result};
 use std::collections::HashMap;
 use nom::lib::std::collections::HashSet;
+use serde::Serialize;
+use std::io::Write;
 #[macro_use]
 extern crate lazy_static;
 
@@ -96,6 +98,151 @@ impl Passport {
     fn valid_for_pt2(&self) -> bool {
         self.valid_for_pt1() && self.fields.iter().all(|(key, value)| key.validate(value))
     }
+
+    fn field(&self, key: PassportDataKey) -> Option<&str> {
+        self.fields.get(&key).map(|value| value.as_str())
+    }
+
+    fn normalize(&self) -> NormalizedPassport {
+        let year = |key| self.field(key).and_then(parse_digits);
+        NormalizedPassport {
+            birth_year: year(PassportDataKey::BirthYear),
+            issue_year: year(PassportDataKey::IssueYear),
+            expiration_year: year(PassportDataKey::ExpirationYear),
+            height: self.field(PassportDataKey::Height).and_then(Height::parse),
+            hair_color: self.field(PassportDataKey::HairColor).and_then(Rgb::parse),
+            eye_color: self.field(PassportDataKey::EyeColor).map(|value| value.to_string()),
+            passport_id: self.field(PassportDataKey::PassportID).map(|value| value.to_string()),
+            country_id: self.field(PassportDataKey::CountryId).map(|value| value.to_string()),
+            valid_pt1: self.valid_for_pt1(),
+            valid_pt2: self.valid_for_pt2(),
+        }
+    }
+}
+
+// digits only: `parse` alone would also take a sign
+fn parse_digits(digits: &str) -> Option<i32> {
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None
+    }
+    digits.parse().ok()
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum HeightUnit {
+    Cm,
+    In
+}
+
+#[derive(Serialize, Debug)]
+struct Height {
+    value: i32,
+    unit: HeightUnit
+}
+
+impl Height {
+    fn parse(value: &str) -> Option<Height> {
+        let unit = if value.ends_with("cm") {
+            HeightUnit::Cm
+        } else if value.ends_with("in") {
+            HeightUnit::In
+        } else {
+            return None
+        };
+        let value = parse_digits(&value[.. value.len() - 2])?;
+        Some(Height { value, unit })
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Rgb {
+    r: u8,
+    g: u8,
+    b: u8
+}
+
+impl Rgb {
+    fn parse(value: &str) -> Option<Rgb> {
+        // `from_str_radix` would also take a sign, so check for exactly six hex digits first
+        if value.len() != 7 || !value.starts_with('#') || !value[1 ..].chars().all(|c| c.is_ascii_hexdigit()) {
+            return None
+        }
+        let channel = |idx: usize| u8::from_str_radix(&value[idx .. idx + 2], 16).ok();
+        Some(Rgb { r: channel(1)?, g: channel(3)?, b: channel(5)? })
+    }
+}
+
+/// A passport with each field converted to its natural type.  Fields that are missing or that
+/// can't be interpreted (e.g. a height without a unit) are `None`; the raw validity flags are
+/// kept alongside so the record can be filtered after export.
+#[derive(Serialize, Debug)]
+struct NormalizedPassport {
+    birth_year: Option<i32>,
+    issue_year: Option<i32>,
+    expiration_year: Option<i32>,
+    height: Option<Height>,
+    hair_color: Option<Rgb>,
+    eye_color: Option<String>,
+    passport_id: Option<String>,
+    country_id: Option<String>,
+    valid_pt1: bool,
+    valid_pt2: bool,
+}
+
+// csv can't serialize nested structs, so flatten height/color into their own columns
+#[derive(Serialize)]
+struct PassportCsvRow<'a> {
+    birth_year: Option<i32>,
+    issue_year: Option<i32>,
+    expiration_year: Option<i32>,
+    height_value: Option<i32>,
+    height_unit: Option<HeightUnit>,
+    hair_color_r: Option<u8>,
+    hair_color_g: Option<u8>,
+    hair_color_b: Option<u8>,
+    eye_color: Option<&'a str>,
+    passport_id: Option<&'a str>,
+    country_id: Option<&'a str>,
+    valid_pt1: bool,
+    valid_pt2: bool,
+}
+
+impl<'a> From<&'a NormalizedPassport> for PassportCsvRow<'a> {
+    fn from(passport: &'a NormalizedPassport) -> Self {
+        PassportCsvRow {
+            birth_year: passport.birth_year,
+            issue_year: passport.issue_year,
+            expiration_year: passport.expiration_year,
+            height_value: passport.height.as_ref().map(|h| h.value),
+            height_unit: passport.height.as_ref().map(|h| h.unit),
+            hair_color_r: passport.hair_color.as_ref().map(|c| c.r),
+            hair_color_g: passport.hair_color.as_ref().map(|c| c.g),
+            hair_color_b: passport.hair_color.as_ref().map(|c| c.b),
+            eye_color: passport.eye_color.as_deref(),
+            passport_id: passport.passport_id.as_deref(),
+            country_id: passport.country_id.as_deref(),
+            valid_pt1: passport.valid_pt1,
+            valid_pt2: passport.valid_pt2,
+        }
+    }
+}
+
+fn export_json_lines<W: Write>(passports: &[NormalizedPassport], mut out: W) -> Result<()> {
+    for passport in passports {
+        serde_json::to_writer(&mut out, passport)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn export_csv<W: Write>(passports: &[NormalizedPassport], out: W) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    for passport in passports {
+        writer.serialize(PassportCsvRow::from(passport))?;
+    }
+    writer.flush()?;
+    Ok(())
 }
 
 
@@ -108,6 +255,23 @@ fn main() -> Result<()> {
         passports.push(Passport::parse(passport_line)?);
     }
 
+    // `d4p1 export json|csv` writes the normalized batch to stdout instead of the puzzle answers
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let [cmd, format] = args.as_slice() {
+        if cmd == "export" {
+            let normalized: Vec<NormalizedPassport> = passports.iter().map(|p| p.normalize()).collect();
+            let stdout = std::io::stdout();
+            return match format.as_str() {
+                "json" => export_json_lines(&normalized, stdout.lock()),
+                "csv" => export_csv(&normalized, stdout.lock()),
+                _ => bail!("Unknown export format: {}", format),
+            }
+        }
+    }
+    if !args.is_empty() {
+        bail!("Usage: d4p1 [export json|csv]");
+    }
+
     let pt1_valid_count = passports.iter().filter(|p| p.valid_for_pt1()).count();
     println!("There are {} valid passports", pt1_valid_count);
 
@@ -115,4 +279,31 @@ fn main() -> Result<()> {
     println!("There are {} valid passports round 2", pt2_valid_count);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn height_must_be_unsigned() {
+        assert_eq!(Height::parse("183cm").map(|height| height.value), Some(183));
+        assert!(Height::parse("-5cm").is_none());
+        assert!(Height::parse("+60in").is_none());
+        assert!(Height::parse("cm").is_none());
+    }
+
+    #[test]
+    fn years_must_be_unsigned() {
+        let passport = Passport::parse("byr:1980 iyr:+2015 eyr:-2025").unwrap().normalize();
+        assert_eq!((passport.birth_year, passport.issue_year, passport.expiration_year), (Some(1980), None, None));
+    }
+
+    #[test]
+    fn hair_colour_channels_are_two_hex_digits() {
+        let rgb = Rgb::parse("#0a0b0c").unwrap();
+        assert_eq!((rgb.r, rgb.g, rgb.b), (10, 11, 12));
+        assert!(Rgb::parse("#+a+b+c").is_none());
+        assert!(Rgb::parse("#0a0b0g").is_none());
+    }
+}
@@ -1,57 +1,331 @@
 use aoc_2020::parse_input;
 use anyhow::{bail, Result};
-use std::collections::HashSet;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
 
-struct SeatPosition {
-    row: usize,
-    col: usize,
+/// One dimension of a binary space partition: `bits` characters, each choosing the lower (`low`)
+/// or upper (`high`) half of the remaining range.
+struct BspAxis {
+    bits: u32,
+    low: char,
+    high: char,
 }
 
-impl SeatPosition {
-    fn seat_id(&self) -> usize {
-        (self.row * 8) + self.col
+impl BspAxis {
+    fn size(&self) -> usize {
+        1 << self.bits
     }
+}
+
+/// Encodes/decodes boarding passes made of one or more BSP axes, most significant axis first.
+struct BspCodec {
+    axes: Vec<BspAxis>,
+}
 
-    fn parse(instr: &str) -> Result<SeatPosition> {
-        if instr.len() != 10 {
+impl BspCodec {
+    fn new(axes: Vec<BspAxis>) -> Result<BspCodec> {
+        if axes.is_empty() {
+            bail!("A seat encoding needs at least one axis");
+        }
+        if axes.iter().map(|axis| axis.bits as usize).sum::<usize>() >= usize::BITS as usize {
+            bail!("Seat encoding is too large");
+        }
+        for axis in axes.iter() {
+            if axis.low == axis.high {
+                bail!("Axis uses '{}' for both halves", axis.low);
+            }
+        }
+        Ok(BspCodec { axes })
+    }
+
+    /// The puzzle's layout: 7 row characters (F/B) followed by 3 column characters (L/R).
+    fn airline() -> BspCodec {
+        BspCodec::new(vec![
+            BspAxis { bits: 7, low: 'F', high: 'B' },
+            BspAxis { bits: 3, low: 'L', high: 'R' },
+        ]).expect("airline layout is valid")
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.axes.iter().map(|axis| axis.bits as usize).sum()
+    }
+
+    fn decode(&self, instr: &str) -> Result<SeatPosition> {
+        if instr.chars().count() != self.encoded_len() {
             bail!("Invalid seat encoding length")
         }
-        let mut row = 0;
-        for  c in instr.chars().take(7) {
-            row *= 2;
-            if c == 'B' {
-                row += 1
-            } else if c != 'F' {
-                bail!("Invalid seat encoding row value");
+        let mut chars = instr.chars();
+        let mut coords = Vec::with_capacity(self.axes.len());
+        for (axis_idx, axis) in self.axes.iter().enumerate() {
+            let mut value = 0;
+            for c in chars.by_ref().take(axis.bits as usize) {
+                value *= 2;
+                if c == axis.high {
+                    value += 1;
+                } else if c != axis.low {
+                    bail!("Invalid seat encoding value '{}' for axis {}", c, axis_idx);
+                }
             }
-        };
-        let mut col = 0;
-        for c in instr.chars().skip(7) {
-            col *= 2;
-            if c == 'R' {
-                col += 1;
-            } else if c != 'L' {
-                bail!("Invalid seat encoding col value");
+            coords.push(value);
+        }
+        Ok(SeatPosition { coords })
+    }
+
+    fn encode(&self, seat: &SeatPosition) -> Result<String> {
+        self.check_coords(seat)?;
+        let mut encoded = String::with_capacity(self.encoded_len());
+        for (axis, value) in self.axes.iter().zip(seat.coords.iter()) {
+            for bit in (0 .. axis.bits).rev() {
+                encoded.push(if (value >> bit) & 1 == 1 { axis.high } else { axis.low });
             }
+        }
+        Ok(encoded)
+    }
+
+    /// Mixed-radix seat id: each axis is scaled by the size of every axis after it, so the
+    /// airline layout gives `row * 8 + col`.
+    fn seat_id(&self, seat: &SeatPosition) -> Result<usize> {
+        self.check_coords(seat)?;
+        Ok(self.axes.iter().zip(seat.coords.iter()).fold(0, |id, (axis, value)| id * axis.size() + value))
+    }
+
+    fn seat_from_id(&self, id: usize) -> Result<SeatPosition> {
+        if id >= self.seat_count() {
+            bail!("Seat id {} is out of range", id);
+        }
+        let mut remaining = id;
+        let mut coords = vec![0; self.axes.len()];
+        for (idx, axis) in self.axes.iter().enumerate().rev() {
+            coords[idx] = remaining % axis.size();
+            remaining /= axis.size();
+        }
+        Ok(SeatPosition { coords })
+    }
+
+    fn seat_count(&self) -> usize {
+        self.axes.iter().map(|axis| axis.size()).product()
+    }
+
+    fn check_coords(&self, seat: &SeatPosition) -> Result<()> {
+        if seat.coords.len() != self.axes.len() {
+            bail!("Seat has {} coordinates, encoding has {} axes", seat.coords.len(), self.axes.len());
+        }
+        for (idx, (axis, value)) in self.axes.iter().zip(seat.coords.iter()).enumerate() {
+            if *value >= axis.size() {
+                bail!("Coordinate {} is out of range for axis {}", value, idx);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct SeatPosition {
+    coords: Vec<usize>,
+}
+
+/// Seats no boarding pass was scanned for
+#[derive(Debug, Default, Eq, PartialEq)]
+struct MissingSeats {
+    /// Seats in the empty rows in front of the first occupied row; these rows don't exist on
+    /// this plane
+    front: usize,
+    /// Seats in the empty rows behind the last occupied row; these rows don't exist on this plane
+    back: usize,
+    /// Runs of consecutive missing ids from the first occupied row to the last
+    interior: Vec<Range<usize>>,
+}
+
+/// Plane layout built from a batch of boarding passes.  The first axis of the codec forms the
+/// rows; every remaining axis is folded into the columns.  Only scanned seats are stored, so
+/// large encodings cost no more than the passes themselves.
+struct SeatMap {
+    row_len: usize,
+    seat_count: usize,
+    // boarding passes scanned for each seat id
+    passes: BTreeMap<usize, Vec<String>>,
+}
+
+impl SeatMap {
+    /// Builds the map from boarding passes already decoded by `codec`
+    fn build<I: IntoIterator<Item = (String, SeatPosition)>>(codec: &BspCodec, passes: I) -> Result<SeatMap> {
+        let row_len = codec.axes.iter().skip(1).map(|axis| axis.size()).product();
+        let mut seats: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (pass, seat) in passes {
+            let id = codec.seat_id(&seat)?;
+            seats.entry(id).or_default().push(pass);
+        }
+        Ok(SeatMap { row_len, seat_count: codec.seat_count(), passes: seats })
+    }
+
+    fn is_scanned(&self, seat_id: usize) -> bool {
+        self.passes.contains_key(&seat_id)
+    }
+
+    fn min_id(&self) -> Option<usize> {
+        self.passes.keys().next().copied()
+    }
+
+    fn max_id(&self) -> Option<usize> {
+        self.passes.keys().next_back().copied()
+    }
+
+    /// Empty rows at either end are only counted; the gaps between scanned seats are listed as
+    /// runs, so the result stays as small as the input
+    fn missing(&self) -> MissingSeats {
+        let (first, last) = match (self.min_id(), self.max_id()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return MissingSeats::default(),
         };
-        Ok(SeatPosition { row, col })
+        let first_row_start = first / self.row_len * self.row_len;
+        let last_row_end = (last / self.row_len + 1) * self.row_len;
+        let mut interior = Vec::new();
+        let mut next = first_row_start;
+        for id in self.passes.keys() {
+            if *id > next {
+                interior.push(next .. *id);
+            }
+            next = id + 1;
+        }
+        if next < last_row_end {
+            interior.push(next .. last_row_end);
+        }
+        MissingSeats { front: first_row_start, back: self.seat_count - last_row_end, interior }
+    }
+
+    /// Seat ids that more than one boarding pass decoded to, with the offending passes
+    fn duplicates(&self) -> Vec<(usize, &[String])> {
+        self.passes.iter()
+            .filter(|(_, passes)| passes.len() > 1)
+            .map(|(id, passes)| (*id, passes.as_slice()))
+            .collect()
+    }
+}
+
+impl fmt::Display for SeatMap {
+    // '#' scanned, '.' missing, 'D' scanned more than once
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row_idx in 0 .. self.seat_count / self.row_len {
+            write!(f, "{:>4} ", row_idx)?;
+            for seat_id in row_idx * self.row_len .. (row_idx + 1) * self.row_len {
+                let c = match self.passes.get(&seat_id).map(Vec::len) {
+                    None => '.',
+                    Some(1) => '#',
+                    Some(_) => 'D',
+                };
+                write!(f, "{}", c)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
     }
 }
 
 fn main() -> Result<()> {
-    let seats = parse_input(5, |line| SeatPosition::parse(line))?;
+    let codec = BspCodec::airline();
+    let passes = parse_input(5, |line| codec.decode(line).map(|seat| (line.to_string(), seat)))?;
+    let seat_map = SeatMap::build(&codec, passes)?;
 
-    let max_id = seats.iter().map(|seat| seat.seat_id()).max().unwrap();
-    println!("Max seat id: {}", max_id);
+    if std::env::args().any(|arg| arg == "--map") {
+        print!("{}", seat_map);
+    }
 
-    let min_id = seats.iter().map(|seat| seat.seat_id()).min().unwrap();
+    let max_id = seat_map.max_id().unwrap();
+    println!("Max seat id: {} ({})", max_id, codec.encode(&codec.seat_from_id(max_id)?)?);
 
-    let scanned_ids : HashSet<usize> = seats.iter().map(|seat| seat.seat_id()).collect();
-    for id in min_id ..= max_id {
-        if !scanned_ids.contains(&id) {
-            println!("Missing id: {}", id);
+    let missing = seat_map.missing();
+    println!("Missing seats: {} front, {} back", missing.front, missing.back);
+    for gap in missing.interior.iter() {
+        if gap.len() == 1 {
+            println!("Missing id: {}", gap.start);
+        } else {
+            println!("Missing ids: {}..={}", gap.start, gap.end - 1);
         }
     }
+    // the puzzle's seat is a single-seat gap with scanned seats on both sides
+    for gap in missing.interior.iter().filter(|gap| gap.len() == 1 && gap.start > 0 && seat_map.is_scanned(gap.start - 1) && seat_map.is_scanned(gap.end)) {
+        println!("Your seat: {}", gap.start);
+    }
+
+    for (id, passes) in seat_map.duplicates() {
+        println!("Duplicate seat id {}: {}", id, passes.join(", "));
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(codec: &BspCodec, passes: &[&str]) -> SeatMap {
+        SeatMap::build(codec, passes.iter().map(|pass| (pass.to_string(), codec.decode(pass).unwrap()))).unwrap()
+    }
+
+    #[test]
+    fn airline_pass_round_trips() {
+        let codec = BspCodec::airline();
+        let seat = codec.decode("FBFBBFFRLR").unwrap();
+        assert_eq!(seat.coords, vec![44, 5]);
+        assert_eq!(codec.seat_id(&seat).unwrap(), 357);
+        assert_eq!(codec.seat_from_id(357).unwrap(), seat);
+        assert_eq!(codec.encode(&seat).unwrap(), "FBFBBFFRLR");
+    }
+
+    #[test]
+    fn three_axis_codec() {
+        let codec = BspCodec::new(vec![
+            BspAxis { bits: 1, low: 'U', high: 'D' },
+            BspAxis { bits: 2, low: 'F', high: 'B' },
+            BspAxis { bits: 2, low: 'L', high: 'R' },
+        ]).unwrap();
+        assert_eq!(codec.seat_count(), 32);
+        let seat = codec.decode("DBFRL").unwrap();
+        assert_eq!(seat.coords, vec![1, 2, 2]);
+        assert_eq!(codec.seat_id(&seat).unwrap(), 26);
+        for id in 0 .. codec.seat_count() {
+            let seat = codec.seat_from_id(id).unwrap();
+            assert_eq!(codec.seat_id(&codec.decode(&codec.encode(&seat).unwrap()).unwrap()).unwrap(), id);
+        }
+        assert!(codec.seat_from_id(32).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_passes_and_codecs() {
+        let codec = BspCodec::airline();
+        assert!(codec.decode("FBFBBFFRL").is_err());
+        assert!(codec.decode("FBFBBFFRLRL").is_err());
+        assert!(codec.decode("FBFBBFFRLX").is_err());
+        // the right letters on the wrong axis
+        assert!(codec.decode("FBFBBFLRLR").is_err());
+        assert!(codec.encode(&SeatPosition { coords: vec![128, 0] }).is_err());
+        assert!(codec.seat_id(&SeatPosition { coords: vec![1] }).is_err());
+
+        assert!(BspCodec::new(Vec::new()).is_err());
+        assert!(BspCodec::new(vec![BspAxis { bits: 3, low: 'L', high: 'L' }]).is_err());
+        assert!(BspCodec::new(vec![BspAxis { bits: 32, low: 'F', high: 'B' }, BspAxis { bits: 32, low: 'L', high: 'R' }]).is_err());
+    }
+
+    #[test]
+    fn missing_seats_by_row() {
+        let codec = BspCodec::new(vec![BspAxis { bits: 2, low: 'F', high: 'B' }, BspAxis { bits: 2, low: 'L', high: 'R' }]).unwrap();
+        let map = scan(&codec, &["FBLR", "FBRL", "BFLL", "BFLR", "BFRR", "BFRR"]);
+        assert_eq!(map.missing(), MissingSeats { front: 4, back: 4, interior: vec![4 .. 5, 7 .. 8, 10 .. 11] });
+        assert_eq!(map.duplicates(), vec![(11, &["BFRR".to_string(), "BFRR".to_string()][..])]);
+        assert_eq!(map.to_string(), "   0 ....\n   1 .##.\n   2 ##.D\n   3 ....\n");
+    }
+
+    #[test]
+    fn huge_encodings_are_stored_sparsely() {
+        let codec = BspCodec::new(vec![BspAxis { bits: 31, low: 'F', high: 'B' }, BspAxis { bits: 31, low: 'L', high: 'R' }]).unwrap();
+        let first = format!("{}{}", "F".repeat(31), "L".repeat(31));
+        let last = format!("{}B{}", "F".repeat(30), "R".repeat(31));
+        let map = scan(&codec, &[&first, &last]);
+        let row_len = 1 << 31;
+        let missing = map.missing();
+        assert_eq!((missing.front, missing.back), (0, codec.seat_count() - 2 * row_len));
+        assert_eq!(missing.interior.len(), 1);
+        assert_eq!(missing.interior[0], 1 .. 2 * row_len - 1);
+    }
+}
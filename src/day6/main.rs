@@ -1,32 +1,130 @@
 use aoc_2020::{read_input};
-use anyhow::{bail, Result};
-use std::collections::HashSet;
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, HashMap};
+use bit_vec::BitVec;
 
-struct GroupAnswers {
-    individual_answers: Vec<HashSet<char>>
+/// The set of symbols a survey's questions are labelled with.  Each symbol maps to a bit
+/// position in an `AnswerSet`.
+struct Alphabet {
+    symbols: Vec<char>,
+    positions: HashMap<char, usize>,
 }
 
-impl GroupAnswers {
-    fn parse(input: &str) -> Result<GroupAnswers> {
-        let question_keys = 'a' ..= 'z';
+impl Alphabet {
+    fn new<I: IntoIterator<Item = char>>(symbols: I) -> Result<Alphabet> {
+        let mut alphabet = Alphabet { symbols: Vec::new(), positions: HashMap::new() };
+        for c in symbols {
+            if alphabet.positions.insert(c, alphabet.symbols.len()).is_some() {
+                bail!("Duplicate symbol in alphabet: {}", c);
+            }
+            alphabet.symbols.push(c);
+        }
+        if alphabet.symbols.is_empty() {
+            bail!("Alphabet must not be empty");
+        }
+        Ok(alphabet)
+    }
+
+    /// `lowercase`, `uppercase`, `digits`, `alphanumeric`, or `custom:<symbols>`
+    fn parse(spec: &str) -> Result<Alphabet> {
+        match spec {
+            "lowercase" => Alphabet::new('a' ..= 'z'),
+            "uppercase" => Alphabet::new('A' ..= 'Z'),
+            "digits" => Alphabet::new('0' ..= '9'),
+            "alphanumeric" => Alphabet::new(('0' ..= '9').chain('A' ..= 'Z').chain('a' ..= 'z')),
+            _ => match spec.strip_prefix("custom:") {
+                Some(symbols) => Alphabet::new(symbols.chars()),
+                None => Err(anyhow!("Unrecognized alphabet: {}", spec)),
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.symbols.len()
+    }
+}
+
+/// One member's answers as a bitmask over the alphabet.  Alphabets of up to 32 symbols fit in a
+/// single word; larger ones fall back to a `BitVec`.
+#[derive(Clone)]
+enum AnswerSet {
+    Small(u32),
+    Large(BitVec),
+}
+
+impl AnswerSet {
+    fn empty(alphabet_len: usize) -> AnswerSet {
+        if alphabet_len <= 32 {
+            AnswerSet::Small(0)
+        } else {
+            AnswerSet::Large(BitVec::from_elem(alphabet_len, false))
+        }
+    }
+
+    fn insert(&mut self, position: usize) {
+        match self {
+            AnswerSet::Small(bits) => *bits |= 1 << position,
+            AnswerSet::Large(bits) => bits.set(position, true),
+        }
+    }
+
+    fn contains(&self, position: usize) -> bool {
+        match self {
+            AnswerSet::Small(bits) => bits & (1 << position) != 0,
+            AnswerSet::Large(bits) => bits.get(position).unwrap_or(false),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            AnswerSet::Small(bits) => bits.count_ones() as usize,
+            AnswerSet::Large(bits) => bits.blocks().map(|block| block.count_ones() as usize).sum(),
+        }
+    }
+
+    // both sides are always built from the same alphabet, so the representations match
+    fn union_with(&mut self, other: &AnswerSet) {
+        match (self, other) {
+            (AnswerSet::Small(l), AnswerSet::Small(r)) => *l |= r,
+            (AnswerSet::Large(l), AnswerSet::Large(r)) => { l.or(r); },
+            _ => panic!("Mismatched answer set representations"),
+        }
+    }
+
+    fn intersect_with(&mut self, other: &AnswerSet) {
+        match (self, other) {
+            (AnswerSet::Small(l), AnswerSet::Small(r)) => *l &= r,
+            (AnswerSet::Large(l), AnswerSet::Large(r)) => { l.and(r); },
+            _ => panic!("Mismatched answer set representations"),
+        }
+    }
+}
+
+struct GroupAnswers<'a> {
+    alphabet: &'a Alphabet,
+    individual_answers: Vec<AnswerSet>
+}
+
+impl<'a> GroupAnswers<'a> {
+    fn parse(input: &str, alphabet: &'a Alphabet) -> Result<GroupAnswers<'a>> {
         let mut individual_answers = Vec::new();
         for line in input.lines() {
-            let mut answers = HashSet::new();
+            let mut answers = AnswerSet::empty(alphabet.len());
             for c in line.chars() {
-                if !question_keys.contains(&c) {
-                    bail!("Invalid answer set: {}", line);
+                match alphabet.positions.get(&c) {
+                    Some(position) => answers.insert(*position),
+                    None => bail!("Invalid answer set: {}", line),
                 }
-                answers.insert(c);
             }
             individual_answers.push(answers);
         }
-        Ok(GroupAnswers { individual_answers })
+        Ok(GroupAnswers { alphabet, individual_answers })
     }
 
     fn distinct_any_count(&self) -> usize {
-        let mut union : HashSet<char> = HashSet::new();
+        let mut union = AnswerSet::empty(self.alphabet.len());
         for answers in self.individual_answers.iter() {
-            union.extend(answers);
+            union.union_with(answers);
         }
         union.len()
     }
@@ -36,23 +134,131 @@ impl GroupAnswers {
         if let Some(first) = iter.next() {
             let mut intersection = first.clone();
             for answers in iter {
-                intersection = intersection.intersection(answers).copied().collect();
+                intersection.intersect_with(answers);
             }
             intersection.len()
         } else {
             0
         }
     }
+
+    /// How many members of the group answered each question
+    fn frequencies(&self) -> BTreeMap<char, usize> {
+        let mut counts = BTreeMap::new();
+        for (position, c) in self.alphabet.symbols.iter().enumerate() {
+            let count = self.individual_answers.iter().filter(|answers| answers.contains(position)).count();
+            if count > 0 {
+                counts.insert(*c, count);
+            }
+        }
+        counts
+    }
+
+    fn select(&self, query: &GroupQuery) -> Vec<char> {
+        let members = self.individual_answers.len();
+        self.frequencies().into_iter()
+            .filter(|(_, count)| query.matches(*count, members))
+            .map(|(c, _)| c)
+            .collect()
+    }
+}
+
+/// A set-algebra question asked of each group, phrased in terms of how many members answered
+/// a given question.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum GroupQuery {
+    /// Union: answered by anyone
+    Any,
+    /// Intersection: answered by everyone
+    All,
+    /// Answered by at least k members
+    AtLeast(usize),
+    /// Answered by exactly one member
+    ExactlyOne,
+    /// Symmetric difference across all members: answered by an odd number of them
+    SymmetricDifference,
+}
+
+impl GroupQuery {
+    fn parse(name: &str, arg: Option<&str>) -> Result<GroupQuery> {
+        match (name, arg) {
+            ("any", None) => Ok(GroupQuery::Any),
+            ("all", None) => Ok(GroupQuery::All),
+            ("at-least", Some(k)) => Ok(GroupQuery::AtLeast(k.parse()?)),
+            ("exactly-one", None) => Ok(GroupQuery::ExactlyOne),
+            ("symdiff", None) => Ok(GroupQuery::SymmetricDifference),
+            _ => Err(anyhow!("Unrecognized query: {} {}", name, arg.unwrap_or(""))),
+        }
+    }
+
+    fn matches(&self, answered: usize, members: usize) -> bool {
+        match self {
+            GroupQuery::Any => answered >= 1,
+            GroupQuery::All => answered == members,
+            GroupQuery::AtLeast(k) => answered >= *k,
+            GroupQuery::ExactlyOne => answered == 1,
+            GroupQuery::SymmetricDifference => answered % 2 == 1,
+        }
+    }
 }
 
+/// Question -> (groups where anyone answered it, total members who answered it)
+fn frequency_table(groups: &[GroupAnswers]) -> BTreeMap<char, (usize, usize)> {
+    let mut table = BTreeMap::new();
+    for group in groups {
+        for (c, count) in group.frequencies() {
+            let entry = table.entry(c).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += count;
+        }
+    }
+    table
+}
+
+const USAGE: &str = "Usage: d6p1 [--input FILE] [--alphabet lowercase|uppercase|digits|alphanumeric|custom:SYMBOLS] [any | all | at-least K | exactly-one | symdiff | freq]";
+
 
 
 fn main() -> Result<()> {
-    let input = read_input(6)?;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut input_path = None;
+    let mut alphabet_spec = "lowercase".to_string();
+    while let Some(flag) = args.first().filter(|arg| arg.starts_with("--")).cloned() {
+        let value = args.get(1).ok_or_else(|| anyhow!(USAGE))?.clone();
+        match flag.as_str() {
+            "--input" => input_path = Some(value),
+            "--alphabet" => alphabet_spec = value,
+            _ => bail!(USAGE),
+        }
+        args.drain(.. 2);
+    }
+    let input = match input_path {
+        Some(path) => std::fs::read_to_string(path)?,
+        None => read_input(6)?,
+    };
+    let alphabet = Alphabet::parse(&alphabet_spec)?;
 
     let mut groups = Vec::new();
     for group_input in input.split("\n\n") {
-        groups.push(GroupAnswers::parse(group_input)?);
+        groups.push(GroupAnswers::parse(group_input, &alphabet)?);
+    }
+
+    match args.as_slice() {
+        [] => {},
+        [cmd] if cmd == "freq" => {
+            println!("question  groups  members");
+            for (c, (group_count, member_count)) in frequency_table(&groups) {
+                println!("{:>8}  {:>6}  {:>7}", c, group_count, member_count);
+            }
+            return Ok(());
+        },
+        [name] | [name, _] => {
+            let query = GroupQuery::parse(name, args.get(1).map(|arg| arg.as_str()))?;
+            let sum: usize = groups.iter().map(|group| group.select(&query).len()).sum();
+            println!("{:?} sum: {}", query, sum);
+            return Ok(());
+        },
+        _ => bail!(USAGE),
     }
 
     let part_1_sum : usize = groups.iter().map(|group| group.distinct_any_count()).sum();
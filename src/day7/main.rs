@@ -1,25 +1,97 @@
-use anyhow::{bail, Result, anyhow};
+use anyhow::{anyhow, bail, Result};
 use aoc_2020::{parse_input};
-use std::collections::HashSet;
-use nom::lib::std::collections::HashMap;
+use aoc_2020::graph::{Graph, NodeId};
+use std::collections::{HashMap, HashSet};
+use thiserror::Error;
+use nom::{IResult, Finish};
+use nom::branch::alt;
+use nom::bytes::complete::{tag, take_while1};
+use nom::character::complete::{char, digit1, space1};
+use nom::combinator::{cut, eof, map, map_res, opt, verify};
+use nom::error::{context, ErrorKind, VerboseError, VerboseErrorKind};
+use nom::multi::separated_list1;
+use nom::sequence::{pair, tuple};
 
 struct BagRule {
     bag_type: String,
     can_contain: Vec<BagQty>
 }
 
+type ParseResult<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
+
+#[derive(Error, Debug)]
+#[error("invalid bag rule at column {column}: expected {expected}")]
+struct RuleParseError {
+    column: usize,
+    expected: String,
+}
+
+impl RuleParseError {
+    // Reports the position where parsing stopped, in characters, described by the innermost
+    // grammar context
+    fn from_verbose(line: &str, err: VerboseError<&str>) -> RuleParseError {
+        let column = err.errors.first().map(|(rest, _)| line[.. line.len() - rest.len()].chars().count() + 1).unwrap_or(1);
+        let expected = err.errors.iter()
+            .find_map(|(_, kind)| match kind {
+                VerboseErrorKind::Context(context) => Some(context.to_string()),
+                _ => None,
+            })
+            .or_else(|| err.errors.first().map(|(_, kind)| match kind {
+                VerboseErrorKind::Char(c) => format!("'{}'", c),
+                VerboseErrorKind::Context(context) => context.to_string(),
+                VerboseErrorKind::Nom(ErrorKind::Space) | VerboseErrorKind::Nom(ErrorKind::MultiSpace) => "whitespace".to_string(),
+                VerboseErrorKind::Nom(ErrorKind::Digit) => "a number".to_string(),
+                VerboseErrorKind::Nom(ErrorKind::Eof) => "end of rule".to_string(),
+                VerboseErrorKind::Nom(_) => "bag rule".to_string(),
+            }))
+            .unwrap_or_else(|| "bag rule".to_string());
+        RuleParseError { column, expected }
+    }
+}
+
 impl BagRule {
+    /// `<colour> bag(s) contain <contents>[.]`, where contents is either `no other bags` or a
+    /// comma separated list of `<qty> <colour> bag(s)`.  Colours may be any number of words.
     fn parse(line: &str) -> Result<BagRule> {
-        match line.split(" bags contain ").collect::<Vec<&str>>().as_slice() {
-            [container_desc, rest] => {
-                Ok(BagRule {
-                    bag_type: container_desc.to_string(),
-                    can_contain: BagQty::parse(rest)?
-                })
-            },
-            _ => bail!("Unparseable rule")
-        }
+        let rule = BagRule::rule(line)
+            .finish()
+            .map(|(_, rule)| rule)
+            .map_err(|err| RuleParseError::from_verbose(line, err))?;
+        Ok(rule)
     }
+
+    fn rule(input: &str) -> ParseResult<'_, BagRule> {
+        map(
+            tuple((
+                context("bag colour", bag_color),
+                space,
+                context("\"bag\" or \"bags\"", bag_word),
+                space,
+                context("\"contain\"", tag("contain")),
+                space,
+                context("bag contents", BagQty::contents),
+                opt(context("'.'", char('.'))),
+                context("end of rule", eof),
+            )),
+            |(bag_type, _, _, _, _, _, can_contain, _, _)| BagRule { bag_type, can_contain }
+        )(input)
+    }
+}
+
+fn bag_color(input: &str) -> ParseResult<'_, String> {
+    let word = verify(
+        take_while1(|c: char| c.is_alphanumeric() || c == '-'),
+        |word: &str| word != "bag" && word != "bags" && word != "contain"
+    );
+    map(separated_list1(space1, word), |words| words.join(" "))(input)
+}
+
+fn space(input: &str) -> ParseResult<'_, &str> {
+    context("whitespace", space1)(input)
+}
+
+fn bag_word(input: &str) -> ParseResult<'_, &str> {
+    alt((tag("bags"), tag("bag")))(input)
 }
 
 struct BagQty {
@@ -27,108 +99,358 @@ struct BagQty {
     qty: usize
 }
 impl BagQty {
-    fn parse(rule: &str) -> Result<Vec<BagQty>> {
-        if rule == "no other bags." {
-            return Ok(Vec::new())
-        }
-        let mut quantities = Vec::new();
-        for fragment in rule.split(", ")  {
-            let tokens: Vec<&str> = fragment.split_whitespace().collect();
-            if let [qty, adj, color, _bag] = tokens.as_slice() {
-                let qty : usize = qty.parse()?;
-                quantities.push(BagQty {
-                    bag_type: format!("{} {}", adj, color),
-                    qty
-                });
-            } else {
-                bail!("Bad rule fragment: {}", fragment)
-            }
-        }
-        Ok(quantities)
+    fn contents(input: &str) -> ParseResult<'_, Vec<BagQty>> {
+        alt((
+            map(tag("no other bags"), |_| Vec::new()),
+            // once a ',' is seen another quantity must follow, so errors there can't backtrack
+            // into a misleading "end of rule"
+            separated_list1(pair(char(','), cut(context("\", \" between contents", space1))), cut(BagQty::quantity)),
+        ))(input)
+    }
+
+    fn quantity(input: &str) -> ParseResult<'_, BagQty> {
+        map(
+            tuple((
+                context("quantity", map_res(digit1, |qty: &str| qty.parse::<usize>())),
+                space,
+                context("bag colour", bag_color),
+                space,
+                context("\"bag\" or \"bags\"", bag_word),
+            )),
+            |(qty, _, bag_type, _, _)| BagQty { bag_type, qty }
+        )(input)
     }
 }
 
 struct RuleSystem {
-    bag_can_contain: HashMap<String, Vec<BagQty>>
+    graph: Graph<String, usize>
 }
 impl RuleSystem {
-    fn from_axioms(axioms: Vec<BagRule>) -> RuleSystem {
-        let mut rules = HashMap::new();
+    /// A bag named more than once as contents of the same bag, whether within one rule or across
+    /// several rules for that bag, becomes one edge with the quantities added up, so path
+    /// quantities agree with `contained_counts`
+    fn from_axioms(axioms: Vec<BagRule>) -> Result<RuleSystem> {
+        let mut merged: Vec<(String, Vec<BagQty>)> = Vec::with_capacity(axioms.len());
+        let mut rule_of: HashMap<String, usize> = HashMap::new();
         for rule in axioms {
-            rules.insert(rule.bag_type, rule.can_contain);
+            let idx = *rule_of.entry(rule.bag_type.clone()).or_insert_with(|| {
+                merged.push((rule.bag_type.clone(), Vec::new()));
+                merged.len() - 1
+            });
+            let contents = &mut merged[idx].1;
+            for contained in rule.can_contain {
+                match contents.iter_mut().find(|bag| bag.bag_type == contained.bag_type) {
+                    Some(bag) => bag.qty = bag.qty.checked_add(contained.qty).ok_or_else(|| anyhow!("Bag count overflowed"))?,
+                    None => contents.push(contained),
+                }
+            }
         }
-        let missing : Vec<String> = rules.values().flatten().map(|r| r.bag_type.clone()).filter(|bt| !rules.contains_key(bt)).collect();
-        for bag_type in missing {
-            rules.insert(bag_type, Vec::new());
+        let mut graph = Graph::new();
+        for (bag_type, contents) in merged {
+            graph.add_node(bag_type.clone());
+            for contained in contents {
+                graph.add_edge(bag_type.clone(), contained.bag_type, contained.qty);
+            }
         }
-        RuleSystem { bag_can_contain: rules }
+        Ok(RuleSystem { graph })
+    }
+
+    fn contained_count(&self, bag_type: &str) -> Result<usize> {
+        let id = self.graph.id(bag_type)?;
+        let order = self.graph.topological_order_from(Some(id))?;
+        let counts = self.contained_counts_for(&order)?;
+        Ok(counts[id.index()].expect("root is part of its own ordering"))
     }
-    fn invert(&self) -> InvertedRuleSystem {
-        let mut inverted = HashMap::new();
 
-        for (key, values) in self.bag_can_contain.iter() {
-            for value in values {
-                inverted.entry(value.bag_type.clone()).or_insert_with(|| HashSet::new()).insert(key.clone());
+    /// Total bags contained by every bag type, computed in a single pass over the graph
+    fn contained_counts(&self) -> Result<HashMap<String, usize>> {
+        let order = self.graph.topological_order()?;
+        let counts = self.contained_counts_for(&order)?;
+        Ok(order.into_iter().map(|id| (self.graph.node(id).clone(), counts[id.index()].unwrap())).collect())
+    }
+
+    // Walks `order` backwards so every contained bag is totalled before its containers.  The
+    // result is indexed by node id; nodes outside `order` are left as `None`.
+    fn contained_counts_for(&self, order: &[NodeId]) -> Result<Vec<Option<usize>>> {
+        let mut counts: Vec<Option<usize>> = vec![None; self.graph.len()];
+        for id in order.iter().rev() {
+            let mut count: usize = 0;
+            for (contained, qty) in self.graph.successors(*id) {
+                let inner = counts[contained.index()].expect("contained bags are counted first");
+                // each contained bag counts itself plus everything inside it
+                count = inner.checked_add(1)
+                    .and_then(|per_bag| per_bag.checked_mul(*qty))
+                    .and_then(|bags| count.checked_add(bags))
+                    .ok_or_else(|| anyhow!("Bag count overflowed for {}", self.graph.node(*id)))?;
             }
+            counts[id.index()] = Some(count);
         }
-        for key in self.bag_can_contain.keys().filter(|k| !inverted.contains_key(*k)).collect::<Vec<&String>>() {
-            inverted.insert(key.clone(), HashSet::new());
-        }
-        InvertedRuleSystem { bag_contained_by: inverted }
+        Ok(counts)
     }
-    fn contained_count(&self, bag_type: &str) -> Result<usize> {
-       self.contained_count_inner(&bag_type.to_string(), &mut HashSet::new())
+
+    fn eventual_containers(&self, bag_type: &str) -> Result<HashSet<String>> {
+        let id = self.graph.id(bag_type)?;
+        Ok(self.graph.reaching(id).into_iter().map(|container| self.graph.node(container).clone()).collect())
     }
 
-    fn contained_count_inner(&self, bag_type: &String, stack: &mut HashSet<String>) -> Result<usize> {
-        if stack.contains(bag_type) {
-            bail!("Cycle detected");
-        }
-        stack.insert(bag_type.clone());
-        let mut count= 0;
-        let contained_bags = self.bag_can_contain.get(bag_type).ok_or(anyhow!("Unknown bag type"))?;
-        for contained in contained_bags {
-            count += contained.qty;
-            count += contained.qty * self.contained_count_inner(&contained.bag_type, stack)?;
-        }
-        stack.remove(bag_type);
-        Ok(count)
+    /// Bag types that hold exactly `total` bags altogether
+    fn bags_with_total(&self, total: usize) -> Result<Vec<String>> {
+        let mut matching: Vec<String> = self.contained_counts()?.into_iter()
+            .filter(|(_, count)| *count == total)
+            .map(|(bag_type, _)| bag_type)
+            .collect();
+        matching.sort();
+        Ok(matching)
+    }
+
+    /// The bag that can hold `target` while holding the fewest bags in total, i.e. the cheapest
+    /// choice of outermost bag for carrying `target`.  Ties go to the alphabetically first bag.
+    fn smallest_container(&self, target: &str) -> Result<Option<(String, usize)>> {
+        let containers = self.eventual_containers(target)?;
+        let counts = self.contained_counts()?;
+        Ok(containers.into_iter()
+            .map(|bag_type| {
+                let count = counts[&bag_type];
+                (bag_type, count)
+            })
+            .min_by(|(l_type, l_count), (r_type, r_count)| l_count.cmp(r_count).then_with(|| l_type.cmp(r_type))))
+    }
+
+    /// Every way `outer` eventually holds `inner`, with the number of `inner` bags each way yields
+    fn containment_paths(&self, outer: &str, inner: &str) -> Result<Vec<(Vec<String>, usize)>> {
+        let (outer, inner) = (self.graph.id(outer)?, self.graph.id(inner)?);
+        self.graph.all_paths(outer, inner).into_iter().map(|path| {
+            let mut qty: usize = 1;
+            for pair in path.windows(2) {
+                let edge_qty = self.graph.edge(pair[0], pair[1]).expect("path follows existing edges");
+                qty = qty.checked_mul(*edge_qty).ok_or_else(|| anyhow!("Bag count overflowed"))?;
+            }
+            Ok((self.graph.names(&path), qty))
+        }).collect()
+    }
+
+    /// The shortest and longest nesting chains from `outer` down to `inner`
+    fn nesting_chains(&self, outer: &str, inner: &str) -> Result<Option<(Vec<String>, Vec<String>)>> {
+        let (outer, inner) = (self.graph.id(outer)?, self.graph.id(inner)?);
+        let shortest = match self.graph.shortest_path(outer, inner) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let longest = self.graph.longest_path(outer, inner)?.expect("shortest path exists");
+        Ok(Some((self.graph.names(&shortest), self.graph.names(&longest))))
+    }
+
+    /// How many levels of bags are nested inside `bag_type`, or inside any bag if `None`
+    fn max_depth(&self, bag_type: Option<&str>) -> Result<usize> {
+        let depths = self.graph.depths()?;
+        Ok(match bag_type {
+            Some(bag_type) => depths[self.graph.id(bag_type)?.index()],
+            None => depths.into_iter().max().unwrap_or(0),
+        })
+    }
+
+    /// Bags that no other bag can hold
+    fn roots(&self) -> Vec<String> {
+        let mut roots = self.graph.names(&self.graph.roots());
+        roots.sort();
+        roots
+    }
+
+    /// Bags that can't hold any other bag
+    fn leaves(&self) -> Vec<String> {
+        let mut leaves = self.graph.names(&self.graph.leaves());
+        leaves.sort();
+        leaves
+    }
+
+    fn export_nodes(&self, scope: &ExportScope) -> Result<HashSet<NodeId>> {
+        let (id, mut nodes) = match scope {
+            ExportScope::All => return Ok(self.graph.node_ids().collect()),
+            ExportScope::ContainersOf(bag_type) => {
+                let id = self.graph.id(bag_type.as_str())?;
+                (id, self.graph.reaching(id))
+            },
+            ExportScope::ContentsOf(bag_type) => {
+                let id = self.graph.id(bag_type.as_str())?;
+                (id, self.graph.reachable_from(id))
+            },
+        };
+        nodes.insert(id);
+        Ok(nodes)
+    }
+
+    /// Renders the bags in `scope`, highlighting every bag on a path from the first bag of
+    /// `highlight` to the second
+    fn export(&self, format: ExportFormat, scope: &ExportScope, highlight: Option<(&str, &str)>) -> Result<String> {
+        let nodes = self.export_nodes(scope)?;
+        let highlighted = match highlight {
+            Some((from, to)) => self.graph.nodes_between(self.graph.id(from)?, self.graph.id(to)?),
+            None => HashSet::new(),
+        };
+        Ok(match format {
+            ExportFormat::Dot => self.graph.to_dot(&nodes, &highlighted),
+            ExportFormat::Mermaid => self.graph.to_mermaid(&nodes, &highlighted),
+        })
     }
 }
 
-struct InvertedRuleSystem {
-    bag_contained_by: HashMap<String, HashSet<String>>
+#[derive(Debug, Clone, Copy)]
+enum ExportFormat {
+    Dot,
+    Mermaid,
 }
 
-impl InvertedRuleSystem {
-    // todo: handle missing bag_type and cycles in the graph
-    fn eventual_containers(&self, bag_type: &str) -> HashSet<String> {
-        let mut results = HashSet::new();
-        let mut search_queue = Vec::new();
-        search_queue.push(bag_type.to_string());
-        while !search_queue.is_empty() {
-            let search = search_queue.pop().unwrap();
-            for container in self.bag_contained_by.get(&search).expect("System wasn't symmetric") {
-                if !results.contains(container) {
-                    results.insert(container.clone());
-                    search_queue.push(container.clone());
-                }
-            }
+#[derive(Debug, Clone)]
+enum ExportScope {
+    All,
+    /// The bag and everything that can eventually hold it
+    ContainersOf(String),
+    /// The bag and everything it eventually holds
+    ContentsOf(String),
+}
+
+const USAGE: &str = "Usage: d7p1 [export dot|mermaid [--containers BAG | --contents BAG] [--highlight FROM TO]
+            | paths OUTER INNER | chain OUTER INNER | depth [BAG] | roots | leaves
+            | total N | smallest-container BAG]";
+
+fn export(system: &RuleSystem, args: &[String]) -> Result<()> {
+    let format = match args.first().map(|arg| arg.as_str()) {
+        Some("dot") => ExportFormat::Dot,
+        Some("mermaid") => ExportFormat::Mermaid,
+        _ => bail!(USAGE),
+    };
+    let mut scope = ExportScope::All;
+    let mut highlight = None;
+    let mut rest = &args[1 ..];
+    loop {
+        match rest {
+            [] => break,
+            [flag, bag_type, tail @ ..] if flag == "--containers" => {
+                scope = ExportScope::ContainersOf(bag_type.clone());
+                rest = tail;
+            },
+            [flag, bag_type, tail @ ..] if flag == "--contents" => {
+                scope = ExportScope::ContentsOf(bag_type.clone());
+                rest = tail;
+            },
+            [flag, from, to, tail @ ..] if flag == "--highlight" => {
+                highlight = Some((from.as_str(), to.as_str()));
+                rest = tail;
+            },
+            _ => bail!(USAGE),
         }
-        results
     }
+    print!("{}", system.export(format, &scope, highlight)?);
+    Ok(())
+}
+
+fn query(system: &RuleSystem, cmd: &str, args: &[String]) -> Result<()> {
+    match (cmd, args) {
+        ("paths", [outer, inner]) => {
+            let paths = system.containment_paths(outer, inner)?;
+            for (path, qty) in paths.iter() {
+                println!("{} x {}", qty, path.join(" > "));
+            }
+            let total = paths.iter().try_fold(0usize, |total, (_, qty)| total.checked_add(*qty))
+                .ok_or_else(|| anyhow!("Bag count overflowed"))?;
+            println!("{} paths, {} {} bags in total", paths.len(), total, inner);
+        },
+        ("chain", [outer, inner]) => match system.nesting_chains(outer, inner)? {
+            Some((shortest, longest)) => {
+                println!("shortest ({} levels): {}", shortest.len() - 1, shortest.join(" > "));
+                println!("longest ({} levels): {}", longest.len() - 1, longest.join(" > "));
+            },
+            None => println!("{} can't hold {}", outer, inner),
+        },
+        ("depth", []) => println!("max nesting depth: {}", system.max_depth(None)?),
+        ("depth", [bag_type]) => println!("{} nesting depth: {}", bag_type, system.max_depth(Some(bag_type))?),
+        ("roots", []) => system.roots().iter().for_each(|bag_type| println!("{}", bag_type)),
+        ("leaves", []) => system.leaves().iter().for_each(|bag_type| println!("{}", bag_type)),
+        ("total", [total]) => system.bags_with_total(total.parse()?)?.iter().for_each(|bag_type| println!("{}", bag_type)),
+        ("smallest-container", [target]) => match system.smallest_container(target)? {
+            Some((bag_type, count)) => println!("{} holds {} with {} bags in total", bag_type, target, count),
+            None => println!("nothing can hold {}", target),
+        },
+        _ => bail!(USAGE),
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
-    let input = parse_input(7, |line| BagRule::parse(line))?;
+    let input = parse_input(7, BagRule::parse)?;
+
+    let system = RuleSystem::from_axioms(input)?;
 
-    let system = RuleSystem::from_axioms(input);
-    let inverted = system.invert();
-    let inverted_count = inverted.eventual_containers("shiny gold").len();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.split_first() {
+        None => {},
+        Some((cmd, rest)) if cmd == "export" => return export(&system, rest),
+        Some((cmd, rest)) => return query(&system, cmd, rest),
+    }
+
+    let inverted_count = system.eventual_containers("shiny gold")?.len();
     println!("shiny gold can be contained by {} bag types", inverted_count);
 
     let contained_count = system.contained_count("shiny gold")?;
     println!("shiny gold contains {} bags", contained_count);
 
+    let all_counts = system.contained_counts()?;
+    if let Some((bag_type, count)) = all_counts.iter().max_by_key(|(_, count)| **count) {
+        println!("{} contains the most bags: {}", bag_type, count);
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(line: &str) -> String {
+        BagRule::parse(line).err().expect("rule should be rejected").to_string()
+    }
+
+    #[test]
+    fn parses_multi_word_colours() {
+        let rule = BagRule::parse("light red bags contain 1 bright white bag, 2 muted yellow bags.").unwrap();
+        assert_eq!(rule.bag_type, "light red");
+        let contents: Vec<(&str, usize)> = rule.can_contain.iter().map(|bag| (bag.bag_type.as_str(), bag.qty)).collect();
+        assert_eq!(contents, vec![("bright white", 1), ("muted yellow", 2)]);
+    }
+
+    #[test]
+    fn errors_name_what_was_expected() {
+        assert_eq!(error("light red bags contain1 bright white bag."), "invalid bag rule at column 23: expected whitespace");
+        assert_eq!(error("light red bags contain two bright white bags."), "invalid bag rule at column 24: expected quantity");
+        assert_eq!(error("light red bags hold 1 bright white bag."), "invalid bag rule at column 16: expected \"contain\"");
+        assert_eq!(error("light red bags contain 1 bright white bag,2 muted yellow bags."), "invalid bag rule at column 43: expected \", \" between contents");
+        assert_eq!(error("light red bags contain 1 bright white bag, two muted yellow bags."), "invalid bag rule at column 44: expected quantity");
+        assert_eq!(error("light red bags contain 1 bright white bag. 2 muted yellow bags."), "invalid bag rule at column 43: expected end of rule");
+        // columns count characters, not bytes
+        assert_eq!(error("café red bags contain1 bright white bag."), "invalid bag rule at column 22: expected whitespace");
+    }
+
+    #[test]
+    fn repeated_bags_merge_into_one_edge() {
+        let rules = [
+            "light red bags contain 1 bright white bag, 2 shiny gold bags, 3 bright white bags.",
+            "bright white bags contain 1 shiny gold bag.",
+            "shiny gold bags contain no other bags.",
+        ];
+        let system = RuleSystem::from_axioms(rules.iter().map(|line| BagRule::parse(line).unwrap()).collect()).unwrap();
+        let paths = system.containment_paths("light red", "shiny gold").unwrap();
+        assert_eq!(paths.iter().map(|(_, qty)| qty).sum::<usize>(), 6);
+        assert_eq!(system.contained_counts().unwrap()["light red"], 4 + 2 + 4);
+
+        // the same bag defined on two lines
+        let rules = [
+            "light red bags contain 2 shiny gold bags.",
+            "light red bags contain 3 shiny gold bags.",
+            "shiny gold bags contain no other bags.",
+        ];
+        let system = RuleSystem::from_axioms(rules.iter().map(|line| BagRule::parse(line).unwrap()).collect()).unwrap();
+        let paths = system.containment_paths("light red", "shiny gold").unwrap();
+        assert_eq!(paths, vec![(vec!["light red".to_string(), "shiny gold".to_string()], 5)]);
+        assert_eq!(system.contained_counts().unwrap()["light red"], 5);
+    }
+}
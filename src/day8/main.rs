@@ -1,113 +1,135 @@
-use anyhow::{Result, anyhow, bail};
-use thiserror::Error;
-use bit_vec::BitVec;
+use anyhow::{Result, bail};
 use aoc_2020::parse_input;
-use std::num::TryFromIntError;
-use std::convert::TryFrom;
+use aoc_2020::vm::{execute, ArithmeticMode, ExecutionError, Instruction, LoopDetection, Machine, OpcodeTable, RunPolicy};
+use aoc_2020::vm::asm::{assemble, disassemble};
+use aoc_2020::vm::cfg::ControlFlowGraph;
+use aoc_2020::vm::debugger::Debugger;
+use aoc_2020::vm::repair::find_repairs;
+use aoc_2020::vm::trace::Trace;
+use std::time::Duration;
 
+const USAGE: &str = "Usage: d8p1 [trace text|json | replay TRACE_FILE | debug | disasm | source | asm SOURCE_FILE
+            | run [--max-steps N] [--timeout-ms N] [--loop off|ip|state]
+                [--arith checked|wrapping|saturating|i64|i128]]";
 
-#[derive(Debug, Clone, Eq, PartialEq)]
-enum Instruction {
-    Nop(isize),
-    Acc(i32),
-    Jmp(isize)
-}
-
-impl Instruction {
-    fn parse(line: &str) -> Result<Instruction> {
-        match &line.split_whitespace().collect::<Vec<_>>()[..] {
-            &["nop", off] => {
-                let offset = off.parse()?;
-                Ok(Instruction::Nop(offset))
+fn run(instructions: Vec<Instruction>, args: &[String]) -> Result<()> {
+    let mut policy = RunPolicy::default();
+    let mut arithmetic = ArithmeticMode::default();
+    for flag in args.chunks(2) {
+        match flag {
+            [name, value] if name == "--max-steps" => policy.max_steps = Some(value.parse()?),
+            [name, value] if name == "--timeout-ms" => policy.time_budget = Some(Duration::from_millis(value.parse()?)),
+            [name, value] if name == "--loop" => policy.loop_detection = match value.as_str() {
+                "off" => LoopDetection::Off,
+                "ip" => LoopDetection::Ip,
+                "state" => LoopDetection::State,
+                _ => bail!(USAGE),
             },
-            &["acc", off] => {
-                let offset = off.parse()?;
-                Ok(Instruction::Acc(offset))
+            [name, value] if name == "--arith" => arithmetic = match value.as_str() {
+                "checked" => ArithmeticMode::Checked,
+                "wrapping" => ArithmeticMode::Wrapping,
+                "saturating" => ArithmeticMode::Saturating,
+                "i64" => ArithmeticMode::Wide64,
+                "i128" => ArithmeticMode::Wide128,
+                _ => bail!(USAGE),
             },
-            &["jmp", off] => {
-                let offset = off.parse()?;
-                Ok(Instruction::Jmp(offset))
-            }
-            _ => Err(anyhow!("Unrecognized instruction")),
+            _ => bail!(USAGE),
         }
     }
-}
-
-#[derive(Error, Debug)]
-enum ExecutionError {
-    #[error("execution failed due to infinite loop (next instr: {next_instruction}, current accum: {acc_state})")]
-    LoopDetected {
-        next_instruction: usize,
-        acc_state: i32
-    },
-    #[error("execution exited with an unexpected instruction pointer (should be: {instr_len}, pointer was: {next_instruction})")]
-    InvalidExitPointer {
-        next_instruction: usize,
-        instr_len: usize
-    },
-    #[error("error during pointer arithmetic")]
-    PointerLogicError {
-        #[from]
-        from: TryFromIntError
+    let mut machine = Machine::new(instructions);
+    machine.set_arithmetic_mode(arithmetic);
+    let report = machine.run_with(&policy);
+    match report.result {
+        Ok(acc) => println!("Program terminated with accumulator {}", acc),
+        Err(err) => println!("{}", err),
+    }
+    let stats = report.stats;
+    println!("Steps executed: {}", stats.steps);
+    println!("Instructions touched: {}", stats.instructions_touched);
+    if let Some((ip, count)) = stats.hottest {
+        println!("Hottest instruction: {} ({} executions)", ip, count);
     }
+    println!("Elapsed: {:?}", stats.elapsed);
+    Ok(())
 }
 
-fn execute(instructions: &Vec<Instruction>) -> std::result::Result<i32, ExecutionError> {
-    let mut loop_detect = BitVec::from_elem(instructions.len(), false);
-    let mut accum = 0;
-    let mut instr_ptr= 0;
-    while let Some(instr) = instructions.get(instr_ptr) {
-        if loop_detect.get(instr_ptr).expect("vec lengths should be the same") {
-            return Err(ExecutionError::LoopDetected { next_instruction: instr_ptr, acc_state: accum });
-        }
-        loop_detect.set(instr_ptr, true);
-        match instr {
-            Instruction::Nop(_) => {
-                instr_ptr += 1;
-            },
-            Instruction::Acc(value) => {
-                instr_ptr += 1;
-                accum += value;
-            },
-            Instruction::Jmp(offset) => {
-                let ptr = isize::try_from(instr_ptr)?;
-                instr_ptr = usize::try_from(ptr + offset)?;
+fn main() -> Result<()> {
+    let instructions = parse_input(8, Instruction::parse)?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.as_slice() {
+        [] => {},
+        [cmd, format] if cmd == "trace" => {
+            let mut machine = Machine::new(instructions);
+            machine.enable_trace();
+            let result = machine.run();
+            let trace = machine.take_trace().unwrap();
+            match format.as_str() {
+                "text" => print!("{}", trace.to_text()),
+                "json" => println!("{}", trace.to_json()?),
+                _ => bail!(USAGE),
             }
-        }
-    }
-    if instr_ptr == instructions.len() {
-        Ok(accum)
-    } else {
-        Err(ExecutionError::InvalidExitPointer {
-            next_instruction: instr_ptr,
-            instr_len: instructions.len()
-        })
+            if let Err(err) = result {
+                eprintln!("{}", err);
+            }
+            return Ok(());
+        },
+        [cmd, path] if cmd == "replay" => {
+            let trace = Trace::from_json(&std::fs::read_to_string(path)?)?;
+            let acc = trace.replay(&mut Machine::new(instructions))?;
+            println!("Replayed {} steps, accumulator: {}", trace.steps.len(), acc);
+            return Ok(());
+        },
+        [cmd] if cmd == "debug" => {
+            let mut debugger = Debugger::new(Machine::new(instructions));
+            let stdin = std::io::stdin();
+            return debugger.repl(stdin.lock(), &mut std::io::stdout());
+        },
+        [cmd] if cmd == "disasm" => {
+            let cfg = ControlFlowGraph::build(&instructions);
+            println!("; {} instructions in {} blocks", instructions.len(), cfg.blocks.len());
+            println!("; {} unreachable instructions", cfg.unreachable_instructions().len());
+            for jump in cfg.invalid_jumps.iter() {
+                println!("; jump at {} leaves the program (target {})", jump.ip, jump.target);
+            }
+            print!("{}", cfg.disassemble(&instructions));
+            return Ok(());
+        },
+        [cmd] if cmd == "source" => {
+            print!("{}", disassemble(&instructions, &OpcodeTable::new()));
+            return Ok(());
+        },
+        [cmd, path] if cmd == "asm" => {
+            let program = assemble(&std::fs::read_to_string(path)?, &OpcodeTable::new())?;
+            println!("Assembled {} instructions", program.len());
+            match execute(&program) {
+                Ok(acc) => println!("Program terminated with accumulator {}", acc),
+                Err(err) => println!("{}", err),
+            }
+            return Ok(());
+        },
+        [cmd, rest @ ..] if cmd == "run" => return run(instructions, rest),
+        _ => bail!(USAGE),
     }
-}
 
-fn try_mutate(instructions: &mut Vec<Instruction>) -> Result<i32> {
-    for idx in 0..instructions.len() {
-        let original_instruction = &instructions[idx];
-        let updated_instruction = match original_instruction {
-            Instruction::Nop(offset) => Instruction::Jmp(*offset),
-            Instruction::Jmp(offset) => Instruction::Nop(*offset),
-            _ => continue,
-        };
-        let original_instruction = std::mem::replace(&mut instructions[idx], updated_instruction);
-        if let Ok(acc) = execute(instructions) {
-            return Ok(acc);
+    match execute(&instructions) {
+        Ok(acc) => println!("Program terminated with accumulator {}", acc),
+        Err(err) => {
+            println!("{}", err);
+            if let ExecutionError::LoopDetected { cycle, .. } = err {
+                for (ip, instr) in cycle {
+                    println!("  {:>5}: {}", ip, instr);
+                }
+            }
         }
-        instructions[idx] = original_instruction;
     }
-    bail!("Couldn't mutate instruction set to success");
-}
 
-fn main() -> Result<()> {
-    let mut instructions = parse_input(8, |line| Instruction::parse(line))?;
-
-    let result = execute(&instructions);
-    dbg!(result);
-
-    dbg!(try_mutate(&mut instructions));
+    let repairs = find_repairs(&instructions)?;
+    if repairs.is_empty() {
+        bail!("Couldn't mutate instruction set to success");
+    }
+    for repair in repairs {
+        println!("Repair: {} at {} -> {}, accumulator: {}", repair.original, repair.ip, repair.replacement, repair.acc);
+    }
     Ok(())
-}
\ No newline at end of file
+}
@@ -1,7 +1,10 @@
-use anyhow::{Result as AResult};
+use anyhow::{Result as AResult, anyhow, bail};
 use thiserror::Error;
 use std::collections::{VecDeque, HashMap};
-use aoc_2020::parse_input;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use aoc_2020::load_input;
+use itertools::Itertools;
 
 #[derive(Error, Debug)]
 enum ConsumptionError {
@@ -9,52 +12,113 @@ enum ConsumptionError {
     InvalidValue(i128)
 }
 
+/// Which sums of the window make a value valid
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum SumRule {
+    /// Two window values that differ from each other (the puzzle's rule)
+    DistinctValues,
+    /// Two window entries at different positions, even if they hold the same value
+    DistinctPositions,
+    /// `k` window entries at different positions.  Checked by trying every combination, so
+    /// keep `k` small.
+    Terms(usize),
+}
+
+impl SumRule {
+    fn parse(spec: &str) -> AResult<SumRule> {
+        Ok(match spec {
+            "values" => SumRule::DistinctValues,
+            "positions" => SumRule::DistinctPositions,
+            _ => match spec.strip_prefix("sum:") {
+                Some(k) => SumRule::Terms(k.parse()?),
+                None => bail!("Unknown sum rule: {}", spec),
+            },
+        })
+    }
+
+    fn pairs(&self, l: i128, r: i128) -> bool {
+        match self {
+            SumRule::DistinctValues => l != r,
+            _ => true,
+        }
+    }
+}
+
 struct XmasPreamble {
     window_size: usize,
+    rule: SumRule,
     window: VecDeque<i128>,
+    /// How many times each value appears in the window
+    multiplicities: HashMap<i128, usize>,
+    /// Number of qualifying pairs per sum; only maintained for the pairwise rules
     sums: HashMap<i128, usize>
 }
 impl XmasPreamble {
-    fn new(size: usize) -> XmasPreamble {
-        let mut preamble = VecDeque::with_capacity(size);
+    fn new(size: usize, rule: SumRule) -> XmasPreamble {
+        let preamble = VecDeque::with_capacity(size);
         XmasPreamble {
             window_size: size,
+            rule,
             window: preamble,
+            multiplicities: HashMap::with_capacity(size),
             sums: HashMap::with_capacity(size * size)
         }
     }
+
+    fn is_valid(&self, next: i128) -> bool {
+        match self.rule {
+            SumRule::Terms(k) => self.window.iter().combinations(k).any(|terms| terms.into_iter().sum::<i128>() == next),
+            _ => self.sums.contains_key(&next),
+        }
+    }
+
+    /// Slides `next` into the window, invalid or not, so the stream can be checked past the
+    /// first bad value.  Values inside the preamble are always valid.
     fn consume(&mut self, next: i128) -> Result<(), ConsumptionError> {
         if self.window.len() != self.window_size {
-            self.increment_sums(next);
-            self.window.push_back(next);
+            self.insert(next);
             Ok(())
         } else {
-            if !self.sums.contains_key(&next) {
-                Err(ConsumptionError::InvalidValue(next))
-            } else {
-                let to_remove = self.window.pop_front().expect("Broken invariant");
-                self.decrement_sums(to_remove);
-                self.increment_sums(next);
-                self.window.push_back(next);
-                Ok(())
-            }
+            let valid = self.is_valid(next);
+            let to_remove = self.window.pop_front().expect("Broken invariant");
+            self.remove(to_remove);
+            self.insert(next);
+            if valid { Ok(()) } else { Err(ConsumptionError::InvalidValue(next)) }
         }
     }
 
-    fn increment_sums(&mut self, next: i128) {
-        for value in self.window.iter() {
-            if *value != next {
-                let mut pair_count = self.sums.entry(value + next).or_insert(0);
-                *pair_count += 1;
+    /// Pairs `value` forms with the current window contents: one per copy of every other value,
+    /// plus one per existing copy of itself when the rule allows equal terms
+    fn pairs_with(&self, value: i128) -> impl Iterator<Item = (i128, usize)> + '_ {
+        let rule = self.rule;
+        self.multiplicities.iter()
+            .filter(move |(other, _)| rule.pairs(**other, value))
+            .map(move |(other, count)| (other + value, *count))
+    }
+
+    fn insert(&mut self, next: i128) {
+        if let SumRule::DistinctValues | SumRule::DistinctPositions = self.rule {
+            let pairs: Vec<(i128, usize)> = self.pairs_with(next).collect();
+            for (sum, count) in pairs {
+                *self.sums.entry(sum).or_insert(0) += count;
             }
         }
+        self.window.push_back(next);
+        *self.multiplicities.entry(next).or_insert(0) += 1;
     }
-    fn decrement_sums(&mut self, old: i128) {
-        for value in self.window.iter() {
-            if *value != old {
-                let sum = value + old;
-                let mut pair_count = self.sums.get_mut(&sum).expect("Broken invariant");
-                *pair_count -= 1;
+
+    /// Forgets one copy of `old`, which must already be out of `window`
+    fn remove(&mut self, old: i128) {
+        let copies = self.multiplicities.get_mut(&old).expect("Broken invariant");
+        *copies -= 1;
+        if *copies == 0 {
+            self.multiplicities.remove(&old);
+        }
+        if let SumRule::DistinctValues | SumRule::DistinctPositions = self.rule {
+            let pairs: Vec<(i128, usize)> = self.pairs_with(old).collect();
+            for (sum, count) in pairs {
+                let pair_count = self.sums.get_mut(&sum).expect("Broken invariant");
+                *pair_count -= count;
                 if *pair_count == 0 {
                     self.sums.remove(&sum);
                 }
@@ -63,39 +127,292 @@ impl XmasPreamble {
     }
 }
 
-fn part1(numbers: &Vec<i128>) -> Result<(), ConsumptionError> {
-    let mut preamble = XmasPreamble::new(25);
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct InvalidValue {
+    /// Position in the stream, preamble included
+    index: usize,
+    value: i128,
+}
+
+/// Yields every invalid value in a stream of numbers, pulling numbers only as needed.  Stop
+/// after the first item to get the puzzle's answer.
+struct Validator<I> {
+    numbers: I,
+    preamble: XmasPreamble,
+    index: usize,
+}
 
-    for number in numbers {
-        preamble.consume(*number)?;
+impl<I: Iterator<Item = AResult<i128>>> Validator<I> {
+    fn new<T: IntoIterator<IntoIter = I>>(numbers: T, preamble_size: usize, rule: SumRule) -> AResult<Validator<I>> {
+        if preamble_size == 0 {
+            bail!("The preamble must hold at least one number");
+        }
+        Ok(Validator { numbers: numbers.into_iter(), preamble: XmasPreamble::new(preamble_size, rule), index: 0 })
     }
-    Ok(())
 }
-fn part2(search: &[i128], sum: i128) -> Option<i128> {
-    let mut cur = 0;
-    // todo: efficiency
-    for start in 0 .. (search.len() - 1) {
-        for end in (start + 1) .. search.len() {
-            let sub = &search[start ..= end];
-            if sub.iter().sum::<i128>() == sum {
-                let min = sub.iter().min().unwrap();
-                let max = sub.iter().max().unwrap();
-                return Some(min + max)
+
+impl<I: Iterator<Item = AResult<i128>>> Iterator for Validator<I> {
+    type Item = AResult<InvalidValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for number in self.numbers.by_ref() {
+            let number = match number {
+                Ok(number) => number,
+                Err(err) => return Some(Err(err)),
+            };
+            let index = self.index;
+            self.index += 1;
+            if let Err(ConsumptionError::InvalidValue(value)) = self.preamble.consume(number) {
+                return Some(Ok(InvalidValue { index, value }));
             }
         }
+        None
+    }
+}
+
+/// Numbers from `path` (or the puzzle input), parsed one line at a time
+fn numbers(path: Option<&str>) -> AResult<impl Iterator<Item = AResult<i128>>> {
+    let file = match path {
+        Some(path) => File::open(path)?,
+        None => load_input(9)?,
+    };
+    Ok(BufReader::new(file).lines().enumerate().map(|(line_num, line)| {
+        let line = line?;
+        line.trim().parse::<i128>().map_err(|err| anyhow!("line {}: \"{}\": {}", line_num + 1, line, err))
+    }))
+}
+
+/// A contiguous run of at least two numbers, `start ..= end`, with its smallest and largest value
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+struct SumRange {
+    start: usize,
+    end: usize,
+    min: i128,
+    max: i128,
+}
+
+impl SumRange {
+    fn len(&self) -> usize {
+        self.end - self.start + 1
+    }
+
+    /// The puzzle's encryption weakness
+    fn weakness(&self) -> i128 {
+        self.min + self.max
+    }
+}
+
+/// Sparse table answering min/max over any slice in constant time
+struct RangeExtremes {
+    /// `levels[k][i]` covers `i .. i + 2^k`
+    levels: Vec<Vec<(i128, i128)>>,
+}
+
+impl RangeExtremes {
+    fn new(numbers: &[i128]) -> RangeExtremes {
+        let mut levels = vec![numbers.iter().map(|n| (*n, *n)).collect::<Vec<_>>()];
+        let mut width = 1;
+        while width * 2 <= numbers.len() {
+            let prev = levels.last().unwrap();
+            let level = (0 ..= numbers.len() - width * 2)
+                .map(|i| (prev[i].0.min(prev[i + width].0), prev[i].1.max(prev[i + width].1)))
+                .collect();
+            levels.push(level);
+            width *= 2;
+        }
+        RangeExtremes { levels }
+    }
+
+    /// Min and max of `start ..= end`
+    fn query(&self, start: usize, end: usize) -> (i128, i128) {
+        let level = (usize::BITS - 1 - (end - start + 1).leading_zeros()) as usize;
+        let (l, r) = (self.levels[level][start], self.levels[level][end + 1 - (1 << level)]);
+        (l.0.min(r.0), l.1.max(r.1))
     }
-    None
 }
 
+/// Every contiguous range of two or more numbers summing to `target`, ordered by start then
+/// end.  Runs on prefix sums, so negative numbers are fine; the cost is linear in the input
+/// plus the number of ranges found (and `n log n` for the min/max table).
+fn contiguous_ranges(numbers: &[i128], target: i128) -> Vec<SumRange> {
+    let extremes = RangeExtremes::new(numbers);
+    // prefix sum -> every `i` with `numbers[.. i]` summing to it
+    let mut prefixes: HashMap<i128, Vec<usize>> = HashMap::new();
+    let mut ranges = Vec::new();
+    let mut prefix = 0;
+    for (end, number) in numbers.iter().enumerate() {
+        if end > 0 {
+            // starts up to `end - 1` only, so every range holds at least two numbers
+            prefixes.entry(prefix - numbers[end - 1]).or_default().push(end - 1);
+        }
+        prefix += number;
+        for start in prefixes.get(&(prefix - target)).into_iter().flatten() {
+            let (min, max) = extremes.query(*start, end);
+            ranges.push(SumRange { start: *start, end, min, max });
+        }
+    }
+    ranges.sort_by_key(|range| (range.start, range.end));
+    ranges
+}
+
+/// Which of the matching ranges to report
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum RangePick {
+    First,
+    Shortest,
+    Longest,
+    All,
+}
+
+impl RangePick {
+    fn parse(spec: &str) -> AResult<RangePick> {
+        Ok(match spec {
+            "first" => RangePick::First,
+            "shortest" => RangePick::Shortest,
+            "longest" => RangePick::Longest,
+            "all" => RangePick::All,
+            _ => bail!("Unknown range selection: {}", spec),
+        })
+    }
+
+    fn select(&self, ranges: Vec<SumRange>) -> Vec<SumRange> {
+        let picked = match self {
+            RangePick::All => return ranges,
+            RangePick::First => ranges.first().copied(),
+            RangePick::Shortest => ranges.iter().min_by_key(|range| range.len()).copied(),
+            RangePick::Longest => ranges.iter().max_by_key(|range| range.len()).copied(),
+        };
+        picked.into_iter().collect()
+    }
+}
+
+const USAGE: &str = "Usage: d9p1 [--input FILE] [--preamble N] [--rule values|positions|sum:K] [--all]
+            [--range first|shortest|longest|all]";
+
 fn main() -> AResult<()> {
-    let numbers = parse_input(9, |line| line.parse::<i128>())?;
+    let mut args = std::env::args().skip(1);
+    let mut input_path = None;
+    let mut preamble_size = 25;
+    let mut rule = SumRule::DistinctValues;
+    let mut report_all = false;
+    let mut pick = RangePick::First;
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--all" => report_all = true,
+            "--input" => input_path = Some(args.next().ok_or_else(|| anyhow!(USAGE))?),
+            "--preamble" => preamble_size = args.next().ok_or_else(|| anyhow!(USAGE))?.parse()?,
+            "--range" => pick = RangePick::parse(&args.next().ok_or_else(|| anyhow!(USAGE))?)?,
+            "--rule" => rule = SumRule::parse(&args.next().ok_or_else(|| anyhow!(USAGE))?)?,
+            _ => bail!(USAGE),
+        }
+    }
+
+    let mut validator = Validator::new(numbers(input_path.as_deref())?, preamble_size, rule)?;
+    if report_all {
+        for invalid in validator {
+            let invalid = invalid?;
+            println!("invalid value {} at {}", invalid.value, invalid.index);
+        }
+        return Ok(());
+    }
 
-    if let Err(ConsumptionError::InvalidValue(value)) = part1(&numbers) {
-        // search everything up to the value; any range including the value (todo: or after the value) will sum too high
-        let err_value_idx = numbers.iter().position(|cur| *cur == value).expect("Broken invariant");
-        let part2 = part2(&numbers[0 .. err_value_idx], value).unwrap();
-        println!("part2: {}", part2)
+    if let Some(invalid) = validator.next().transpose()? {
+        println!("part1: {}", invalid.value);
+        let numbers = numbers(input_path.as_deref())?.collect::<AResult<Vec<i128>>>()?;
+        let ranges = pick.select(contiguous_ranges(&numbers, invalid.value));
+        if ranges.is_empty() {
+            bail!("No contiguous range sums to {}", invalid.value);
+        }
+        for range in ranges {
+            println!("part2: {} (range {}..={}, {} numbers, min {}, max {})",
+                     range.weakness(), range.start, range.end, range.len(), range.min, range.max);
+        }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    /// Pair sums of `preamble`'s window counted from scratch, in the `sums` layout
+    fn recount_sums(preamble: &XmasPreamble) -> HashMap<i128, usize> {
+        let mut sums = HashMap::new();
+        if let SumRule::DistinctValues | SumRule::DistinctPositions = preamble.rule {
+            for (l, r) in preamble.window.iter().tuple_combinations() {
+                if preamble.rule.pairs(*l, *r) {
+                    *sums.entry(l + r).or_insert(0) += 1;
+                }
+            }
+        }
+        sums
+    }
+
+    /// Whether `next` is valid after `window` under `rule`, by trying every pair of positions
+    fn naive_is_valid(window: &[i128], rule: SumRule, next: i128) -> bool {
+        match rule {
+            SumRule::Terms(k) => window.iter().combinations(k).any(|terms| terms.into_iter().sum::<i128>() == next),
+            _ => (0 .. window.len()).any(|i| (i + 1 .. window.len()).any(|j| {
+                rule.pairs(window[i], window[j]) && window[i] + window[j] == next
+            })),
+        }
+    }
+
+    fn assert_matches_reference(stream: &[i128], window_size: usize, rule: SumRule) {
+        let mut preamble = XmasPreamble::new(window_size, rule);
+        for (idx, next) in stream.iter().enumerate() {
+            let window = &stream[idx.saturating_sub(window_size) .. idx];
+            let expected = window.len() < window_size || naive_is_valid(window, rule, *next);
+            assert_eq!(preamble.consume(*next).is_ok(), expected, "{:?} window {:?}, value {}", rule, window, next);
+            assert_eq!(preamble.sums, recount_sums(&preamble), "{:?} after {} values of {:?}", rule, idx + 1, stream);
+        }
+    }
+
+    #[test]
+    fn duplicate_values_in_window() {
+        let stream = [2, 2, 2, 4, 2, 4, 6, 4, 4, 8];
+        assert_matches_reference(&stream, 3, SumRule::DistinctValues);
+        assert_matches_reference(&stream, 3, SumRule::DistinctPositions);
+    }
+
+    #[test]
+    fn distinct_values_rejects_doubled_value() {
+        let mut preamble = XmasPreamble::new(2, SumRule::DistinctValues);
+        for value in [3, 3] {
+            preamble.consume(value).unwrap();
+        }
+        assert!(preamble.consume(6).is_err());
+
+        let mut preamble = XmasPreamble::new(2, SumRule::DistinctPositions);
+        for value in [3, 3] {
+            preamble.consume(value).unwrap();
+        }
+        assert!(preamble.consume(6).is_ok());
+    }
+
+    #[test]
+    fn empty_preamble_is_rejected() {
+        let numbers: Vec<AResult<i128>> = vec![Ok(1), Ok(2)];
+        assert!(Validator::new(numbers, 0, SumRule::DistinctValues).is_err());
+    }
+
+    #[test]
+    fn random_streams_match_reference() {
+        let mut rng = StdRng::seed_from_u64(2020);
+        for _ in 0 .. 2_000 {
+            let window_size = rng.gen_range(1 ..= 8);
+            let rule = match rng.gen_range(0 .. 3) {
+                0 => SumRule::DistinctValues,
+                1 => SumRule::DistinctPositions,
+                _ => SumRule::Terms(rng.gen_range(1 ..= 3)),
+            };
+            // a narrow spread keeps the windows full of duplicates
+            let spread = rng.gen_range(1 ..= 10);
+            let stream: Vec<i128> = (0 .. rng.gen_range(0 .. 60)).map(|_| rng.gen_range(-spread ..= spread)).collect();
+            assert_matches_reference(&stream, window_size, rule);
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::{Debug, Display, Write};
+use std::hash::Hash;
+use thiserror::Error;
+
+/// Interned handle for a node; only meaningful for the graph that issued it.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum GraphError<N: Debug> {
+    #[error("unknown node: {0:?}")]
+    UnknownNode(N),
+    /// The path starts and ends on the same node
+    #[error("cycle detected: {0:?}")]
+    Cycle(Vec<N>),
+}
+
+/// Weighted directed graph.  Nodes are interned on insertion and referred to by `NodeId`
+/// afterwards; both edge directions are indexed so predecessor queries are as cheap as
+/// successor queries.
+#[derive(Debug, Clone)]
+pub struct Graph<N, W> {
+    nodes: Vec<N>,
+    ids: HashMap<N, NodeId>,
+    successors: Vec<Vec<(NodeId, W)>>,
+    predecessors: Vec<Vec<(NodeId, W)>>,
+}
+
+impl<N: Clone + Eq + Hash + Debug, W: Clone> Graph<N, W> {
+    pub fn new() -> Graph<N, W> {
+        Graph {
+            nodes: Vec::new(),
+            ids: HashMap::new(),
+            successors: Vec::new(),
+            predecessors: Vec::new(),
+        }
+    }
+
+    /// Returns the existing id if the node has already been added
+    pub fn add_node(&mut self, node: N) -> NodeId {
+        if let Some(id) = self.ids.get(&node) {
+            return *id;
+        }
+        let id = NodeId(self.nodes.len());
+        self.ids.insert(node.clone(), id);
+        self.nodes.push(node);
+        self.successors.push(Vec::new());
+        self.predecessors.push(Vec::new());
+        id
+    }
+
+    /// Adds both endpoints if they're missing
+    pub fn add_edge(&mut self, from: N, to: N, weight: W) -> (NodeId, NodeId) {
+        let from = self.add_node(from);
+        let to = self.add_node(to);
+        self.successors[from.0].push((to, weight.clone()));
+        self.predecessors[to.0].push((from, weight));
+        (from, to)
+    }
+
+    pub fn id<Q>(&self, node: &Q) -> Result<NodeId, GraphError<N>>
+        where N: Borrow<Q>, Q: Hash + Eq + ToOwned<Owned = N> + ?Sized {
+        self.ids.get(node).copied().ok_or_else(|| GraphError::UnknownNode(node.to_owned()))
+    }
+
+    pub fn node(&self, id: NodeId) -> &N {
+        &self.nodes[id.0]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = NodeId> {
+        (0 .. self.nodes.len()).map(NodeId)
+    }
+
+    pub fn successors(&self, id: NodeId) -> &[(NodeId, W)] {
+        &self.successors[id.0]
+    }
+
+    pub fn predecessors(&self, id: NodeId) -> &[(NodeId, W)] {
+        &self.predecessors[id.0]
+    }
+
+    /// Every node reachable by following edges forwards from `start`, excluding `start` itself
+    /// unless it sits on a cycle
+    pub fn reachable_from(&self, start: NodeId) -> HashSet<NodeId> {
+        self.search(start, &self.successors)
+    }
+
+    /// Every node that can reach `target`, excluding `target` itself unless it sits on a cycle
+    pub fn reaching(&self, target: NodeId) -> HashSet<NodeId> {
+        self.search(target, &self.predecessors)
+    }
+
+    fn search(&self, start: NodeId, adjacency: &[Vec<(NodeId, W)>]) -> HashSet<NodeId> {
+        let mut results = HashSet::new();
+        let mut search_queue = vec![start];
+        while let Some(search) = search_queue.pop() {
+            for (next, _) in adjacency[search.0].iter() {
+                if results.insert(*next) {
+                    search_queue.push(*next);
+                }
+            }
+        }
+        results
+    }
+
+    pub fn names(&self, ids: &[NodeId]) -> Vec<N> {
+        ids.iter().map(|id| self.nodes[id.0].clone()).collect()
+    }
+
+    /// Orders nodes so that every edge points from an earlier node to a later one
+    pub fn topological_order(&self) -> Result<Vec<NodeId>, GraphError<N>> {
+        self.topological_order_from(self.node_ids())
+    }
+
+    /// Like `topological_order`, restricted to the nodes reachable from `roots` (inclusive).
+    /// Only cycles within that subgraph are reported.
+    pub fn topological_order_from<I: IntoIterator<Item = NodeId>>(&self, roots: I) -> Result<Vec<NodeId>, GraphError<N>> {
+        #[derive(Clone, Copy, Eq, PartialEq)]
+        enum Mark { Unvisited, InProgress, Done }
+
+        let mut marks = vec![Mark::Unvisited; self.nodes.len()];
+        let mut postorder = Vec::with_capacity(self.nodes.len());
+        for root in roots {
+            if marks[root.0] != Mark::Unvisited {
+                continue;
+            }
+            // (node, index of the next successor to visit); doubles as the current DFS path
+            let mut stack = vec![(root, 0)];
+            marks[root.0] = Mark::InProgress;
+            while let Some((node, edge_idx)) = stack.last_mut() {
+                let node = *node;
+                if let Some((next, _)) = self.successors[node.0].get(*edge_idx) {
+                    *edge_idx += 1;
+                    match marks[next.0] {
+                        Mark::Unvisited => {
+                            marks[next.0] = Mark::InProgress;
+                            stack.push((*next, 0));
+                        },
+                        Mark::InProgress => {
+                            let start = stack.iter().position(|(id, _)| id == next).expect("in-progress node is on the stack");
+                            let mut path: Vec<NodeId> = stack[start ..].iter().map(|(id, _)| *id).collect();
+                            path.push(*next);
+                            return Err(GraphError::Cycle(self.names(&path)));
+                        },
+                        Mark::Done => {},
+                    }
+                } else {
+                    marks[node.0] = Mark::Done;
+                    postorder.push(node);
+                    stack.pop();
+                }
+            }
+        }
+        postorder.reverse();
+        Ok(postorder)
+    }
+
+    /// Weight of the first edge from `from` to `to`, if there is one
+    pub fn edge(&self, from: NodeId, to: NodeId) -> Option<&W> {
+        self.successors[from.0].iter().find(|(id, _)| *id == to).map(|(_, weight)| weight)
+    }
+
+    /// Nodes with no incoming edges
+    pub fn roots(&self) -> Vec<NodeId> {
+        self.node_ids().filter(|id| self.predecessors[id.0].is_empty()).collect()
+    }
+
+    /// Nodes with no outgoing edges
+    pub fn leaves(&self) -> Vec<NodeId> {
+        self.node_ids().filter(|id| self.successors[id.0].is_empty()).collect()
+    }
+
+    /// Every simple path from `from` to `to`, each listed once even if parallel edges join some
+    /// of its nodes.  The number of paths can grow exponentially with the size of the graph.  A
+    /// node has a single path to itself, consisting of just that node.
+    pub fn all_paths(&self, from: NodeId, to: NodeId) -> Vec<Vec<NodeId>> {
+        if from == to {
+            return vec![vec![from]];
+        }
+        let mut paths = Vec::new();
+        let can_reach_target = self.reaching(to);
+        if can_reach_target.contains(&from) {
+            let mut path = vec![from];
+            self.all_paths_inner(to, &can_reach_target, &mut path, &mut paths);
+        }
+        paths
+    }
+
+    fn all_paths_inner(&self, to: NodeId, can_reach_target: &HashSet<NodeId>, path: &mut Vec<NodeId>, paths: &mut Vec<Vec<NodeId>>) {
+        let current = *path.last().unwrap();
+        for (idx, (next, _)) in self.successors[current.0].iter().enumerate() {
+            // parallel edges lead to the same paths; follow only the first
+            if self.successors[current.0][.. idx].iter().any(|(earlier, _)| earlier == next) {
+                continue;
+            }
+            if *next == to {
+                let mut found = path.clone();
+                found.push(to);
+                paths.push(found);
+            } else if can_reach_target.contains(next) && !path.contains(next) {
+                // only descend into nodes that can still get to the target
+                path.push(*next);
+                self.all_paths_inner(to, can_reach_target, path, paths);
+                path.pop();
+            }
+        }
+    }
+
+    /// Fewest-edge path from `from` to `to`
+    pub fn shortest_path(&self, from: NodeId, to: NodeId) -> Option<Vec<NodeId>> {
+        let mut previous: HashMap<NodeId, NodeId> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(from);
+        while let Some(current) = queue.pop_front() {
+            if current == to {
+                let mut path = vec![to];
+                while let Some(prev) = previous.get(path.last().unwrap()) {
+                    path.push(*prev);
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for (next, _) in self.successors[current.0].iter() {
+                if *next != from && !previous.contains_key(next) {
+                    previous.insert(*next, current);
+                    queue.push_back(*next);
+                }
+            }
+        }
+        None
+    }
+
+    /// Most-edge path from `from` to `to`; the graph reachable from `from` must be acyclic
+    pub fn longest_path(&self, from: NodeId, to: NodeId) -> Result<Option<Vec<NodeId>>, GraphError<N>> {
+        let order = self.topological_order_from(Some(from))?;
+        // longest known distance from `from`, and the predecessor that achieved it
+        let mut best: HashMap<NodeId, (usize, Option<NodeId>)> = HashMap::new();
+        best.insert(from, (0, None));
+        for id in order {
+            let distance = match best.get(&id) {
+                Some((distance, _)) => *distance,
+                None => continue,
+            };
+            for (next, _) in self.successors[id.0].iter() {
+                let entry = best.entry(*next).or_insert((0, None));
+                if entry.1.is_none() || entry.0 < distance + 1 {
+                    *entry = (distance + 1, Some(id));
+                }
+            }
+        }
+        if !best.contains_key(&to) {
+            return Ok(None);
+        }
+        let mut path = vec![to];
+        while let Some((_, Some(prev))) = best.get(path.last().unwrap()) {
+            path.push(*prev);
+        }
+        path.reverse();
+        Ok(Some(path))
+    }
+
+    /// Number of edges on the longest path starting at each node; the graph must be acyclic
+    pub fn depths(&self) -> Result<Vec<usize>, GraphError<N>> {
+        let order = self.topological_order()?;
+        let mut depths = vec![0; self.nodes.len()];
+        for id in order.iter().rev() {
+            depths[id.0] = self.successors[id.0].iter().map(|(next, _)| depths[next.0] + 1).max().unwrap_or(0);
+        }
+        Ok(depths)
+    }
+
+    /// Nodes lying on at least one path from `from` to `to`, including both ends.  Empty if
+    /// `to` isn't reachable.
+    pub fn nodes_between(&self, from: NodeId, to: NodeId) -> HashSet<NodeId> {
+        let downstream = self.reachable_from(from);
+        if from != to && !downstream.contains(&to) {
+            return HashSet::new();
+        }
+        let mut between: HashSet<NodeId> = downstream.intersection(&self.reaching(to)).copied().collect();
+        between.insert(from);
+        between.insert(to);
+        between
+    }
+
+    /// The first cycle found, as a path that starts and ends on the same node
+    pub fn find_cycle(&self) -> Option<Vec<N>> {
+        match self.topological_order() {
+            Err(GraphError::Cycle(path)) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl<N: Clone + Eq + Hash + Debug + Display, W: Clone + Display> Graph<N, W> {
+    /// Graphviz rendering of the nodes in `include` and the edges between them, labelled with
+    /// their weights.  Nodes in `highlight` (and edges joining two of them) are drawn in red.
+    pub fn to_dot(&self, include: &HashSet<NodeId>, highlight: &HashSet<NodeId>) -> String {
+        let mut out = String::from("digraph {\n");
+        for id in self.sorted(include) {
+            let style = if highlight.contains(&id) { " color=red fontcolor=red penwidth=2" } else { "" };
+            writeln!(out, "    n{} [label={}{}];", id.0, dot_quote(&self.nodes[id.0].to_string()), style).unwrap();
+        }
+        for (from, to, weight) in self.edges_within(include) {
+            let style = if highlight.contains(&from) && highlight.contains(&to) { " color=red fontcolor=red penwidth=2" } else { "" };
+            writeln!(out, "    n{} -> n{} [label={}{}];", from.0, to.0, dot_quote(&weight.to_string()), style).unwrap();
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    /// Mermaid flowchart equivalent of `to_dot`
+    pub fn to_mermaid(&self, include: &HashSet<NodeId>, highlight: &HashSet<NodeId>) -> String {
+        let mut out = String::from("flowchart LR\n");
+        for id in self.sorted(include) {
+            writeln!(out, "    n{}[\"{}\"]", id.0, mermaid_escape(&self.nodes[id.0].to_string())).unwrap();
+        }
+        let mut highlighted_edges = Vec::new();
+        for (edge_idx, (from, to, weight)) in self.edges_within(include).into_iter().enumerate() {
+            writeln!(out, "    n{} -->|{}| n{}", from.0, mermaid_escape(&weight.to_string()), to.0).unwrap();
+            if highlight.contains(&from) && highlight.contains(&to) {
+                highlighted_edges.push(edge_idx.to_string());
+            }
+        }
+        let highlighted_nodes: Vec<String> = self.sorted(include).into_iter()
+            .filter(|id| highlight.contains(id))
+            .map(|id| format!("n{}", id.0))
+            .collect();
+        if !highlighted_nodes.is_empty() {
+            out.push_str("    classDef highlight stroke:#f00,stroke-width:2px,color:#f00\n");
+            writeln!(out, "    class {} highlight", highlighted_nodes.join(",")).unwrap();
+        }
+        if !highlighted_edges.is_empty() {
+            writeln!(out, "    linkStyle {} stroke:#f00,stroke-width:2px", highlighted_edges.join(",")).unwrap();
+        }
+        out
+    }
+
+    fn sorted(&self, include: &HashSet<NodeId>) -> Vec<NodeId> {
+        let mut ids: Vec<NodeId> = include.iter().copied().collect();
+        ids.sort();
+        ids
+    }
+
+    fn edges_within(&self, include: &HashSet<NodeId>) -> Vec<(NodeId, NodeId, &W)> {
+        self.sorted(include).into_iter()
+            .flat_map(|from| self.successors[from.0].iter()
+                .filter(|(to, _)| include.contains(to))
+                .map(move |(to, weight)| (from, *to, weight)))
+            .collect()
+    }
+}
+
+fn dot_quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// mermaid labels are quoted; it has no escape for `"` other than the HTML entity
+fn mermaid_escape(text: &str) -> String {
+    text.replace('"', "#quot;")
+}
+
+impl<N: Clone + Eq + Hash + Debug, W: Clone> Default for Graph<N, W> {
+    fn default() -> Self {
+        Graph::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(&str, &str)]) -> Graph<String, u32> {
+        let mut graph = Graph::new();
+        for (from, to) in edges {
+            graph.add_edge(from.to_string(), to.to_string(), 1);
+        }
+        graph
+    }
+
+    fn ids(graph: &Graph<String, u32>, names: &[&str]) -> Vec<NodeId> {
+        names.iter().map(|name| graph.id(*name).unwrap()).collect()
+    }
+
+    #[test]
+    fn topological_order_respects_every_edge() {
+        let graph = graph(&[("a", "b"), ("a", "c"), ("c", "b"), ("b", "d"), ("e", "c")]);
+        let order = graph.topological_order().unwrap();
+        assert_eq!(order.len(), graph.len());
+        let position = |id: NodeId| order.iter().position(|other| *other == id).unwrap();
+        for from in graph.node_ids() {
+            for (to, _) in graph.successors(from) {
+                assert!(position(from) < position(*to));
+            }
+        }
+    }
+
+    #[test]
+    fn cycle_error_names_the_cycle() {
+        let graph = graph(&[("a", "b"), ("b", "c"), ("c", "d"), ("d", "b")]);
+        let cycle = vec!["b", "c", "d", "b"].into_iter().map(String::from).collect();
+        assert_eq!(graph.topological_order(), Err(GraphError::Cycle(cycle)));
+        assert_eq!(graph.find_cycle().unwrap().first().map(String::as_str), Some("b"));
+    }
+
+    #[test]
+    fn unknown_nodes_are_reported() {
+        let graph = graph(&[("a", "b")]);
+        assert_eq!(graph.id("z"), Err(GraphError::UnknownNode("z".to_string())));
+    }
+
+    #[test]
+    fn parallel_edges_give_each_path_once() {
+        let graph = graph(&[("a", "b"), ("a", "b"), ("b", "c"), ("a", "c"), ("a", "c")]);
+        let (a, b, c) = (graph.id("a").unwrap(), graph.id("b").unwrap(), graph.id("c").unwrap());
+        assert_eq!(graph.all_paths(a, c), vec![vec![a, b, c], vec![a, c]]);
+        assert_eq!(graph.all_paths(a, a), vec![vec![a]]);
+        assert!(graph.all_paths(c, a).is_empty());
+    }
+
+    #[test]
+    fn shortest_and_longest_paths() {
+        let graph = graph(&[("a", "b"), ("b", "c"), ("c", "d"), ("a", "d"), ("b", "d")]);
+        let (a, d) = (graph.id("a").unwrap(), graph.id("d").unwrap());
+        assert_eq!(graph.shortest_path(a, d), Some(ids(&graph, &["a", "d"])));
+        assert_eq!(graph.longest_path(a, d), Ok(Some(ids(&graph, &["a", "b", "c", "d"]))));
+        assert_eq!(graph.shortest_path(d, a), None);
+        assert_eq!(graph.longest_path(d, a), Ok(None));
+        let cyclic = self::graph(&[("a", "b"), ("b", "a")]);
+        assert!(matches!(cyclic.longest_path(cyclic.id("a").unwrap(), cyclic.id("b").unwrap()), Err(GraphError::Cycle(_))));
+    }
+}
@@ -1,3 +1,6 @@
+pub mod graph;
+pub mod vm;
+
 use std::path::PathBuf;
 use std::fs::File;
 use thiserror::Error;
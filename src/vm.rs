@@ -0,0 +1,597 @@
+use anyhow::{anyhow, bail, Result};
+use bit_vec::BitVec;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::TryFromIntError;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use serde::{Deserialize, Serialize};
+
+pub mod asm;
+pub mod cfg;
+pub mod debugger;
+pub mod repair;
+pub mod trace;
+
+use trace::{Trace, TraceStep};
+
+/// Index of an opcode registered in an `OpcodeTable`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct OpcodeId(usize);
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Instruction {
+    Nop(isize),
+    Acc(i32),
+    Jmp(isize),
+    /// An opcode registered in the machine's `OpcodeTable`
+    Ext(OpcodeId, isize),
+}
+
+impl Instruction {
+    /// Parses one of the built-in instructions; use `OpcodeTable::parse` to accept registered
+    /// opcodes as well
+    pub fn parse(line: &str) -> Result<Instruction> {
+        OpcodeTable::new().parse(line)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Nop(offset) => write!(f, "nop {:+}", offset),
+            Instruction::Acc(value) => write!(f, "acc {:+}", value),
+            Instruction::Jmp(offset) => write!(f, "jmp {:+}", offset),
+            Instruction::Ext(OpcodeId(id), arg) => write!(f, "ext#{} {:+}", id, arg),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExecutionError {
+    #[error("execution failed due to infinite loop (next instr: {next_instruction}, current accum: {acc_state}, cycle length: {})", .cycle.len())]
+    LoopDetected {
+        next_instruction: usize,
+        acc_state: i128,
+        /// Every instruction in the loop, in execution order, starting at `next_instruction`
+        cycle: Vec<(usize, Instruction)>
+    },
+    #[error("execution exited with an unexpected instruction pointer (should be: {instr_len}, pointer was: {next_instruction})")]
+    InvalidExitPointer {
+        next_instruction: usize,
+        instr_len: usize
+    },
+    #[error("error during pointer arithmetic")]
+    PointerLogicError {
+        #[from]
+        from: TryFromIntError
+    },
+    #[error("execution stopped after {steps} steps (next instr: {next_instruction})")]
+    StepLimitExceeded {
+        steps: u64,
+        next_instruction: usize
+    },
+    #[error("execution ran out of time after {steps} steps (next instr: {next_instruction})")]
+    TimedOut {
+        steps: u64,
+        next_instruction: usize
+    },
+    #[error("accumulator overflowed at instruction {instruction} ({acc} + {value})")]
+    AccumulatorOverflow {
+        instruction: usize,
+        acc: i128,
+        value: i128
+    },
+    #[error("instruction {instruction} left the accumulator at {acc}, outside the arithmetic mode's range")]
+    AccumulatorOutOfRange {
+        instruction: usize,
+        acc: i128
+    },
+    #[error("opcode {0:?} is not registered with this machine")]
+    UnknownOpcode(OpcodeId),
+    #[error("instruction {instruction} failed: {message}")]
+    OpcodeFailed {
+        instruction: usize,
+        message: String
+    },
+}
+
+/// The register file.  `acc` is stored wide enough for every `ArithmeticMode`; the machine's
+/// mode decides which range it's actually kept in.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Registers {
+    pub acc: i128,
+}
+
+/// How `acc` instructions treat results outside the accumulator's range.  Unlike plain `+`,
+/// every mode behaves the same in debug and release builds.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ArithmeticMode {
+    /// 32-bit accumulator; overflow stops execution with `AccumulatorOverflow`
+    #[default]
+    Checked,
+    /// 32-bit accumulator with two's complement wrap-around
+    Wrapping,
+    /// 32-bit accumulator clamped to `i32::MIN ..= i32::MAX`
+    Saturating,
+    /// 64-bit accumulator; overflow is an error
+    Wide64,
+    /// 128-bit accumulator; overflow is an error
+    Wide128,
+}
+
+impl ArithmeticMode {
+    /// `acc + value` in this mode, or `None` on overflow.  `acc` may be outside the mode's range
+    /// (e.g. set by an extension opcode); it's then treated like any other out-of-range result.
+    pub fn add(&self, acc: i128, value: i128) -> Option<i128> {
+        match self {
+            // both agree with 32-bit arithmetic modulo 2^32 / at the bounds
+            ArithmeticMode::Wrapping => self.normalize(acc.wrapping_add(value)),
+            ArithmeticMode::Saturating => self.normalize(acc.saturating_add(value)),
+            _ => acc.checked_add(value).and_then(|acc| self.normalize(acc)),
+        }
+    }
+
+    /// `acc` brought into this mode's range by wrapping or clamping, or `None` for the modes
+    /// where an out-of-range value is an error
+    pub fn normalize(&self, acc: i128) -> Option<i128> {
+        match self {
+            ArithmeticMode::Checked => i32::try_from(acc).ok().map(i128::from),
+            ArithmeticMode::Wrapping => Some(i128::from(acc as i32)),
+            ArithmeticMode::Saturating => Some(acc.clamp(i128::from(i32::MIN), i128::from(i32::MAX))),
+            ArithmeticMode::Wide64 => i64::try_from(acc).ok().map(i128::from),
+            ArithmeticMode::Wide128 => Some(acc),
+        }
+    }
+}
+
+
+/// Where execution continues after an opcode handler runs
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Flow {
+    Next,
+    /// Relative jump from the current instruction
+    Jump(isize),
+}
+
+/// Behaviour of a registered opcode.  Errors are reported as `ExecutionError::OpcodeFailed`.
+pub type OpcodeHandler = fn(&mut Registers, isize) -> std::result::Result<Flow, String>;
+
+struct OpcodeDef {
+    mnemonic: String,
+    handler: OpcodeHandler,
+}
+
+/// Opcodes understood by a machine beyond the built-in `nop`/`acc`/`jmp`
+#[derive(Default)]
+pub struct OpcodeTable {
+    opcodes: Vec<OpcodeDef>,
+    by_mnemonic: HashMap<String, OpcodeId>,
+}
+
+impl OpcodeTable {
+    pub fn new() -> OpcodeTable {
+        OpcodeTable::default()
+    }
+
+    pub fn register(&mut self, mnemonic: &str, handler: OpcodeHandler) -> Result<OpcodeId> {
+        if ["nop", "acc", "jmp"].contains(&mnemonic) || self.by_mnemonic.contains_key(mnemonic) {
+            bail!("Opcode {} is already defined", mnemonic);
+        }
+        let id = OpcodeId(self.opcodes.len());
+        self.opcodes.push(OpcodeDef { mnemonic: mnemonic.to_string(), handler });
+        self.by_mnemonic.insert(mnemonic.to_string(), id);
+        Ok(id)
+    }
+
+    pub fn parse(&self, line: &str) -> Result<Instruction> {
+        match line.split_whitespace().collect::<Vec<_>>()[..] {
+            ["nop", off] => Ok(Instruction::Nop(off.parse()?)),
+            ["acc", off] => Ok(Instruction::Acc(off.parse()?)),
+            ["jmp", off] => Ok(Instruction::Jmp(off.parse()?)),
+            [op, arg] => match self.by_mnemonic.get(op) {
+                Some(id) => Ok(Instruction::Ext(*id, arg.parse()?)),
+                None => Err(anyhow!("Unrecognized instruction")),
+            },
+            _ => Err(anyhow!("Unrecognized instruction")),
+        }
+    }
+
+    pub fn mnemonic(&self, instruction: &Instruction) -> Option<&str> {
+        match instruction {
+            Instruction::Nop(_) => Some("nop"),
+            Instruction::Acc(_) => Some("acc"),
+            Instruction::Jmp(_) => Some("jmp"),
+            Instruction::Ext(OpcodeId(id), _) => self.opcodes.get(*id).map(|def| def.mnemonic.as_str()),
+        }
+    }
+
+    fn handler(&self, id: OpcodeId) -> Option<OpcodeHandler> {
+        self.opcodes.get(id.0).map(|def| def.handler)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StepOutcome {
+    Running,
+    /// The instruction pointer moved exactly one past the end of the program
+    Halted,
+}
+
+/// The handheld's CPU: a program, an instruction pointer and the register file
+pub struct Machine {
+    program: Vec<Instruction>,
+    opcodes: OpcodeTable,
+    ip: usize,
+    registers: Registers,
+    arithmetic: ArithmeticMode,
+    tracer: Option<Trace>,
+}
+
+impl Machine {
+    pub fn new(program: Vec<Instruction>) -> Machine {
+        Machine::with_opcodes(program, OpcodeTable::new())
+    }
+
+    pub fn with_opcodes(program: Vec<Instruction>, opcodes: OpcodeTable) -> Machine {
+        Machine { program, opcodes, ip: 0, registers: Registers::default(), arithmetic: ArithmeticMode::default(), tracer: None }
+    }
+
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    /// Editing the program doesn't reset the machine; call `reset` to run it from the start
+    pub fn program_mut(&mut self) -> &mut Vec<Instruction> {
+        &mut self.program
+    }
+
+    pub fn opcodes(&self) -> &OpcodeTable {
+        &self.opcodes
+    }
+
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    pub fn acc(&self) -> i128 {
+        self.registers.acc
+    }
+
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic
+    }
+
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic = mode;
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn current(&self) -> Option<&Instruction> {
+        self.program.get(self.ip)
+    }
+
+    pub fn reset(&mut self) {
+        self.ip = 0;
+        self.registers = Registers::default();
+    }
+
+    /// Starts recording every executed step, discarding any previous trace
+    pub fn enable_trace(&mut self) {
+        self.tracer = Some(Trace::default());
+    }
+
+    /// Stops tracing and returns what was recorded
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.tracer.take()
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.tracer.as_ref()
+    }
+
+    /// Executes the instruction at the instruction pointer
+    pub fn step(&mut self) -> std::result::Result<StepOutcome, ExecutionError> {
+        let instr = match self.program.get(self.ip) {
+            Some(instr) => instr,
+            None => return self.halt_state(),
+        };
+        let (ip, acc_before) = (self.ip, self.registers.acc);
+        let flow = match instr {
+            Instruction::Nop(_) => Flow::Next,
+            Instruction::Acc(value) => {
+                let value = i128::from(*value);
+                self.registers.acc = self.arithmetic.add(self.registers.acc, value)
+                    .ok_or(ExecutionError::AccumulatorOverflow { instruction: self.ip, acc: self.registers.acc, value })?;
+                Flow::Next
+            },
+            Instruction::Jmp(offset) => Flow::Jump(*offset),
+            Instruction::Ext(id, arg) => {
+                let handler = self.opcodes.handler(*id).ok_or(ExecutionError::UnknownOpcode(*id))?;
+                let flow = handler(&mut self.registers, *arg)
+                    .map_err(|message| ExecutionError::OpcodeFailed { instruction: self.ip, message })?;
+                // handlers can write any value; hold them to the machine's arithmetic mode
+                let acc = self.registers.acc;
+                self.registers.acc = self.arithmetic.normalize(acc)
+                    .ok_or(ExecutionError::AccumulatorOutOfRange { instruction: self.ip, acc })?;
+                flow
+            },
+        };
+        match flow {
+            Flow::Next => self.ip += 1,
+            Flow::Jump(offset) => {
+                // a target past isize::MAX is past the end of any program
+                let target = isize::try_from(self.ip)?.checked_add(offset)
+                    .ok_or(ExecutionError::InvalidExitPointer { next_instruction: usize::MAX, instr_len: self.program.len() })?;
+                self.ip = usize::try_from(target)?;
+            }
+        }
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.steps.push(TraceStep {
+                ip,
+                instruction: self.program[ip].clone(),
+                acc_before,
+                acc_after: self.registers.acc,
+            });
+        }
+        if self.ip < self.program.len() {
+            Ok(StepOutcome::Running)
+        } else {
+            self.halt_state()
+        }
+    }
+
+    fn halt_state(&self) -> std::result::Result<StepOutcome, ExecutionError> {
+        if self.ip == self.program.len() {
+            Ok(StepOutcome::Halted)
+        } else {
+            Err(ExecutionError::InvalidExitPointer {
+                next_instruction: self.ip,
+                instr_len: self.program.len()
+            })
+        }
+    }
+
+    /// Runs until the program halts, returning the accumulator.  Reaching any instruction a
+    /// second time is treated as an infinite loop.
+    pub fn run(&mut self) -> std::result::Result<i128, ExecutionError> {
+        self.run_with(&RunPolicy::default()).result
+    }
+
+    /// Runs until the program halts or `policy` stops it, collecting statistics either way
+    pub fn run_with(&mut self, policy: &RunPolicy) -> RunReport {
+        let started = Instant::now();
+        let mut hits = vec![0u64; self.program.len()];
+        let mut steps = 0u64;
+        let result = self.run_inner(policy, started, &mut hits, &mut steps);
+        let hottest = hits.iter().enumerate()
+            .filter(|(_, count)| **count > 0)
+            .max_by(|(l_ip, l_count), (r_ip, r_count)| l_count.cmp(r_count).then_with(|| r_ip.cmp(l_ip)))
+            .map(|(ip, count)| (ip, *count));
+        let stats = RunStats {
+            steps,
+            instructions_touched: hits.iter().filter(|count| **count > 0).count(),
+            hottest,
+            elapsed: started.elapsed(),
+        };
+        RunReport { result, stats }
+    }
+
+    fn run_inner(&mut self, policy: &RunPolicy, started: Instant, hits: &mut [u64], steps: &mut u64) -> std::result::Result<i128, ExecutionError> {
+        let mut loop_detect = BitVec::from_elem(self.program.len(), false);
+        let mut visited: Vec<usize> = Vec::new();
+        // state loops use Brent's algorithm: compare against one checkpoint, moved up to the
+        // current state whenever the distance to it reaches the next power of two, so memory
+        // stays constant however long the run
+        let mut checkpoint: Option<(usize, Registers)> = None;
+        let (mut power, mut distance) = (1u64, 0u64);
+        loop {
+            let cycle = match policy.loop_detection {
+                LoopDetection::Off => None,
+                LoopDetection::Ip => match loop_detect.get(self.ip) {
+                    Some(true) => {
+                        let start = visited.iter().position(|ip| *ip == self.ip).expect("visited ips are recorded");
+                        Some(visited[start ..].iter().map(|ip| (*ip, self.program[*ip].clone())).collect())
+                    },
+                    _ => None,
+                },
+                LoopDetection::State => match &checkpoint {
+                    Some((ip, registers)) if *ip == self.ip && *registers == self.registers => Some(self.replay_cycle(distance)),
+                    _ => None,
+                },
+            };
+            if let Some(cycle) = cycle {
+                return Err(ExecutionError::LoopDetected {
+                    next_instruction: self.ip,
+                    acc_state: self.registers.acc,
+                    cycle,
+                });
+            }
+            if let Some(max_steps) = policy.max_steps {
+                if *steps >= max_steps {
+                    return Err(ExecutionError::StepLimitExceeded { steps: *steps, next_instruction: self.ip });
+                }
+            }
+            // checking the clock every step would dominate the cost of cheap instructions
+            // (`%` rather than `is_multiple_of`, which needs Rust 1.87)
+            #[allow(clippy::manual_is_multiple_of)]
+            if let Some(budget) = policy.time_budget {
+                if *steps % TIME_CHECK_INTERVAL == 0 && started.elapsed() > budget {
+                    return Err(ExecutionError::TimedOut { steps: *steps, next_instruction: self.ip });
+                }
+            }
+            if self.ip < self.program.len() {
+                hits[self.ip] += 1;
+                match policy.loop_detection {
+                    LoopDetection::Off => {},
+                    LoopDetection::Ip => {
+                        loop_detect.set(self.ip, true);
+                        visited.push(self.ip);
+                    },
+                    LoopDetection::State => {
+                        if checkpoint.is_none() || distance == power {
+                            checkpoint = Some((self.ip, self.registers.clone()));
+                            power *= 2;
+                            distance = 0;
+                        }
+                        distance += 1;
+                    },
+                }
+            }
+            let outcome = self.step()?;
+            *steps += 1;
+            if outcome == StepOutcome::Halted {
+                return Ok(self.registers.acc);
+            }
+        }
+    }
+
+
+    /// The `len` instructions that bring the current state back to itself, found by running
+    /// them again.  Leaves the machine (and any trace) as it was.
+    fn replay_cycle(&mut self, len: u64) -> Vec<(usize, Instruction)> {
+        let (ip, registers, tracer) = (self.ip, self.registers.clone(), self.tracer.take());
+        let mut cycle = Vec::new();
+        for _ in 0 .. len {
+            cycle.push((self.ip, self.program[self.ip].clone()));
+            if self.step().is_err() {
+                break;
+            }
+        }
+        self.ip = ip;
+        self.registers = registers;
+        self.tracer = tracer;
+        cycle
+    }
+}
+
+const TIME_CHECK_INTERVAL: u64 = 1024;
+
+/// How `Machine::run_with` decides a program is stuck
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LoopDetection {
+    Off,
+    /// Any instruction running twice is a loop.  Only sound while nothing but the instruction
+    /// pointer decides control flow, which holds for the built-in instructions.
+    Ip,
+    /// A loop is the same instruction pointer with identical registers.  A loop that keeps
+    /// changing the accumulator never repeats a state, so pair this with a step or time limit.
+    /// Uses constant memory; a loop is reported within about twice its length after entering it.
+    State,
+}
+
+/// Termination policy for `Machine::run_with`; every limit is optional
+#[derive(Debug, Clone)]
+pub struct RunPolicy {
+    pub max_steps: Option<u64>,
+    pub time_budget: Option<Duration>,
+    pub loop_detection: LoopDetection,
+}
+
+impl Default for RunPolicy {
+    fn default() -> Self {
+        RunPolicy { max_steps: None, time_budget: None, loop_detection: LoopDetection::Ip }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RunStats {
+    pub steps: u64,
+    /// Distinct instructions executed at least once
+    pub instructions_touched: usize,
+    /// The most executed instruction and how often it ran
+    pub hottest: Option<(usize, u64)>,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug)]
+pub struct RunReport {
+    pub result: std::result::Result<i128, ExecutionError>,
+    pub stats: RunStats,
+}
+
+/// Runs `instructions` from a fresh machine with no extra opcodes
+pub fn execute(instructions: &[Instruction]) -> std::result::Result<i128, ExecutionError> {
+    Machine::new(instructions.to_vec()).run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_in(mode: ArithmeticMode, program: Vec<Instruction>, opcodes: OpcodeTable) -> std::result::Result<i128, ExecutionError> {
+        let mut machine = Machine::with_opcodes(program, opcodes);
+        machine.set_arithmetic_mode(mode);
+        machine.run()
+    }
+
+    #[test]
+    fn arithmetic_modes_at_the_i32_bounds() {
+        let program = vec![Instruction::Acc(i32::MAX), Instruction::Acc(1)];
+        assert!(matches!(run_in(ArithmeticMode::Checked, program.clone(), OpcodeTable::new()), Err(ExecutionError::AccumulatorOverflow { instruction: 1, .. })));
+        assert_eq!(run_in(ArithmeticMode::Wrapping, program.clone(), OpcodeTable::new()).unwrap(), i128::from(i32::MIN));
+        assert_eq!(run_in(ArithmeticMode::Saturating, program.clone(), OpcodeTable::new()).unwrap(), i128::from(i32::MAX));
+        assert_eq!(run_in(ArithmeticMode::Wide64, program.clone(), OpcodeTable::new()).unwrap(), i128::from(i32::MAX) + 1);
+        assert_eq!(run_in(ArithmeticMode::Wide128, program, OpcodeTable::new()).unwrap(), i128::from(i32::MAX) + 1);
+    }
+
+    #[test]
+    fn extension_opcodes_are_held_to_the_mode() {
+        let run = |mode| {
+            let mut opcodes = OpcodeTable::new();
+            opcodes.register("max", |registers, _| {
+                registers.acc = i128::MAX;
+                Ok(Flow::Next)
+            }).unwrap();
+            let program = vec![opcodes.parse("max +0").unwrap(), Instruction::Acc(1)];
+            run_in(mode, program, opcodes)
+        };
+        assert!(matches!(run(ArithmeticMode::Checked), Err(ExecutionError::AccumulatorOutOfRange { instruction: 0, .. })));
+        assert!(matches!(run(ArithmeticMode::Wide64), Err(ExecutionError::AccumulatorOutOfRange { instruction: 0, .. })));
+        assert_eq!(run(ArithmeticMode::Wrapping).unwrap(), 0);
+        assert_eq!(run(ArithmeticMode::Saturating).unwrap(), i128::from(i32::MAX));
+        assert!(matches!(run(ArithmeticMode::Wide128), Err(ExecutionError::AccumulatorOverflow { instruction: 1, .. })));
+    }
+
+    #[test]
+    fn switching_to_a_narrower_mode_mid_run() {
+        let mut machine = Machine::new(vec![Instruction::Acc(i32::MAX), Instruction::Acc(i32::MAX), Instruction::Acc(-1)]);
+        machine.set_arithmetic_mode(ArithmeticMode::Wide128);
+        machine.step().unwrap();
+        machine.step().unwrap();
+        machine.set_arithmetic_mode(ArithmeticMode::Checked);
+        assert!(matches!(machine.step(), Err(ExecutionError::AccumulatorOverflow { instruction: 2, .. })));
+    }
+
+    #[test]
+    fn step_reports_running_then_halted() {
+        let mut machine = Machine::new(vec![Instruction::Acc(2), Instruction::Nop(5)]);
+        assert_eq!(machine.step().unwrap(), StepOutcome::Running);
+        assert_eq!(machine.step().unwrap(), StepOutcome::Halted);
+        assert_eq!(machine.step().unwrap(), StepOutcome::Halted);
+        assert_eq!(machine.acc(), 2);
+    }
+
+    #[test]
+    fn run_halts_loops_or_jumps_out() {
+        assert_eq!(execute(&[Instruction::Acc(3), Instruction::Jmp(1)]).unwrap(), 3);
+        match execute(&[Instruction::Acc(1), Instruction::Jmp(-1)]) {
+            Err(ExecutionError::LoopDetected { next_instruction: 0, acc_state: 1, cycle }) => {
+                assert_eq!(cycle, vec![(0, Instruction::Acc(1)), (1, Instruction::Jmp(-1))]);
+            },
+            other => panic!("expected a loop, got {:?}", other),
+        }
+        assert!(matches!(execute(&[Instruction::Jmp(3)]), Err(ExecutionError::InvalidExitPointer { next_instruction: 3, instr_len: 1 })));
+        assert!(matches!(execute(&[Instruction::Jmp(-1)]), Err(ExecutionError::PointerLogicError { .. })));
+    }
+
+    #[test]
+    fn jump_overflow_is_an_invalid_exit() {
+        let program = vec![Instruction::Nop(0), Instruction::Jmp(isize::MAX)];
+        assert!(matches!(execute(&program), Err(ExecutionError::InvalidExitPointer { next_instruction: usize::MAX, instr_len: 2 })));
+    }
+}
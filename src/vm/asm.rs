@@ -0,0 +1,216 @@
+use super::cfg::jump_labels;
+use super::{Instruction, OpcodeTable};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use thiserror::Error;
+
+#[derive(Error, Debug, Eq, PartialEq)]
+#[error("line {line}: {message}")]
+pub struct AsmError {
+    /// 1-based source line
+    pub line: usize,
+    pub message: String,
+}
+
+fn error<T>(line: usize, message: String) -> Result<T, AsmError> {
+    Err(AsmError { line, message })
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// An instruction left over once comments, labels and directives are stripped
+struct Statement<'a> {
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+/// Assembles handheld source into instructions.
+///
+/// ```text
+/// ; comments run from `;` to the end of the line
+/// .equ STEP 3          ; named constant, usable anywhere a number is
+/// start:               ; labels name the next instruction
+///     acc +STEP
+///     acc -1
+///     jmp done         ; nop/jmp operands can be labels, resolved to relative offsets
+///     .fill 2 nop +0   ; repeat an instruction
+/// done: jmp end        ; `end` is one past the last instruction, declared or not
+/// ```
+///
+/// Numeric `nop`/`jmp` operands are relative offsets as in the puzzle input, so plain puzzle
+/// programs assemble unchanged.  Mnemonics registered in `opcodes` are accepted too.
+pub fn assemble(source: &str, opcodes: &OpcodeTable) -> Result<Vec<Instruction>, AsmError> {
+    // first pass: strip comments, record labels and constants, expand `.fill`
+    let mut labels: HashMap<&str, usize> = HashMap::new();
+    let mut constants: HashMap<&str, isize> = HashMap::new();
+    let mut statements: Vec<Statement> = Vec::new();
+    for (idx, raw) in source.lines().enumerate() {
+        let line = idx + 1;
+        let mut text = raw.split(';').next().unwrap().trim();
+        if let Some(colon) = text.find(':') {
+            let label = text[.. colon].trim();
+            if !is_identifier(label) {
+                return error(line, format!("invalid label name \"{}\"", label));
+            }
+            if labels.insert(label, statements.len()).is_some() {
+                return error(line, format!("label \"{}\" is already defined", label));
+            }
+            text = text[colon + 1 ..].trim();
+        }
+        let words: Vec<&str> = text.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {},
+            [".equ", name, value] => {
+                if !is_identifier(name) {
+                    return error(line, format!("invalid constant name \"{}\"", name));
+                }
+                let value = value.parse().or_else(|_| error(line, format!("invalid constant value \"{}\"", value)))?;
+                if constants.insert(name, value).is_some() {
+                    return error(line, format!("constant \"{}\" is already defined", name));
+                }
+            },
+            [".fill", count, mnemonic, operands @ ..] => {
+                let count: usize = count.parse().or_else(|_| error(line, format!("invalid repeat count \"{}\"", count)))?;
+                for _ in 0 .. count {
+                    statements.push(Statement { line, mnemonic, operands: operands.to_vec() });
+                }
+            },
+            [directive, ..] if directive.starts_with('.') => return error(line, format!("unknown or malformed directive \"{}\"", text)),
+            [mnemonic, operands @ ..] => statements.push(Statement { line, mnemonic, operands: operands.to_vec() }),
+        }
+    }
+    // `end` always means one past the last instruction; it may only be declared there
+    let end = statements.len();
+    if let Some(declared) = labels.get("end") {
+        if *declared != end {
+            let line = statements[*declared].line;
+            return error(line, "label \"end\" must follow the last instruction".to_string());
+        }
+    }
+
+    // second pass: resolve operands now that every label is known
+    let mut program = Vec::with_capacity(statements.len());
+    for (ip, statement) in statements.iter().enumerate() {
+        let line = statement.line;
+        let operand = match statement.operands.as_slice() {
+            [operand] => *operand,
+            _ => return error(line, format!("{} takes exactly one operand", statement.mnemonic)),
+        };
+        let resolve = |allow_labels: bool| -> Result<isize, AsmError> {
+            if let Ok(value) = operand.parse::<isize>() {
+                return Ok(value);
+            }
+            let (sign, name) = match operand.chars().next() {
+                Some('-') => (-1, &operand[1 ..]),
+                Some('+') => (1, &operand[1 ..]),
+                _ => (1, operand),
+            };
+            if let Some(value) = constants.get(name) {
+                return Ok(sign * value);
+            }
+            let target = if name == "end" { Some(end) } else { labels.get(name).copied() };
+            match target {
+                Some(_) if !allow_labels => error(line, format!("{} can't take a label operand", statement.mnemonic)),
+                Some(_) if sign < 0 || operand.starts_with('+') => error(line, format!("labels can't be signed: \"{}\"", operand)),
+                Some(target) => Ok(isize::try_from(target).unwrap() - isize::try_from(ip).unwrap()),
+                None => error(line, format!("unknown label or constant \"{}\"", name)),
+            }
+        };
+        let instruction = match statement.mnemonic {
+            "nop" => Instruction::Nop(resolve(true)?),
+            "jmp" => Instruction::Jmp(resolve(true)?),
+            "acc" => {
+                let value = resolve(false)?;
+                Instruction::Acc(i32::try_from(value).or_else(|_| error(line, format!("acc operand {} is out of range", value)))?)
+            },
+            mnemonic => {
+                let arg = resolve(true)?;
+                opcodes.parse(&format!("{} {}", mnemonic, arg))
+                    .or_else(|_| error(line, format!("unknown instruction \"{}\"", mnemonic)))?
+            },
+        };
+        program.push(instruction);
+    }
+    Ok(program)
+}
+
+/// Labelled source for `program` that `assemble` turns back into the same instructions.
+/// Jumps that land outside the program keep their numeric offsets.
+pub fn disassemble(program: &[Instruction], opcodes: &OpcodeTable) -> String {
+    let labels = jump_labels(program);
+    let mut out = String::new();
+    for (ip, instr) in program.iter().enumerate() {
+        if let Some(label) = labels.get(&ip) {
+            writeln!(out, "{}:", label).unwrap();
+        }
+        let text = match instr {
+            Instruction::Jmp(offset) => {
+                let label = isize::try_from(ip).ok()
+                    .and_then(|ip| ip.checked_add(*offset))
+                    .and_then(|target| usize::try_from(target).ok())
+                    .and_then(|target| labels.get(&target));
+                match label {
+                    Some(label) => format!("jmp {}", label),
+                    None => instr.to_string(),
+                }
+            },
+            Instruction::Ext(_, arg) => format!("{} {:+}", opcodes.mnemonic(instr).unwrap_or("???"), arg),
+            _ => instr.to_string(),
+        };
+        writeln!(out, "    {}", text).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Flow, Registers};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn double(registers: &mut Registers, _: isize) -> Result<Flow, String> {
+        registers.acc *= 2;
+        Ok(Flow::Next)
+    }
+
+    #[test]
+    fn disassembly_assembles_back_to_the_program() {
+        let mut opcodes = OpcodeTable::new();
+        opcodes.register("dbl", double).unwrap();
+        let mut rng = StdRng::seed_from_u64(2020);
+        for _ in 0 .. 200 {
+            let len = rng.gen_range(0 ..= 12);
+            let program: Vec<Instruction> = (0 .. len)
+                .map(|_| match rng.gen_range(0 .. 4) {
+                    0 => Instruction::Nop(rng.gen_range(-15 ..= 15)),
+                    1 => Instruction::Acc(rng.gen_range(-50 ..= 50)),
+                    2 => Instruction::Jmp(rng.gen_range(-15 ..= 15)),
+                    _ => opcodes.parse(&format!("dbl {:+}", rng.gen_range(-3 ..= 3))).unwrap(),
+                })
+                .collect();
+            let source = disassemble(&program, &opcodes);
+            assert_eq!(assemble(&source, &opcodes), Ok(program), "source:\n{}", source);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_source() {
+        let opcodes = OpcodeTable::new();
+        let line = |source: &str| assemble(source, &opcodes).unwrap_err().line;
+        assert_eq!(line("top: nop +0\ntop: jmp top"), 2);
+        assert_eq!(line("nop +0\nend: acc +1\njmp end"), 2);
+        assert_eq!(line("top: acc +1\njmp -top"), 2);
+        assert_eq!(line("acc +1\nacc +STEP"), 2);
+        let error = assemble("top: nop +0\njmp +top", &opcodes).unwrap_err();
+        assert_eq!(error.message, "labels can't be signed: \"+top\"");
+        let error = assemble(".equ STEP 3\nacc +STPE", &opcodes).unwrap_err();
+        assert_eq!(error.message, "unknown label or constant \"STPE\"");
+    }
+}
@@ -0,0 +1,210 @@
+use super::Instruction;
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fmt::Write;
+
+/// Where control can go when a block finishes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Target {
+    /// Index into `ControlFlowGraph::blocks`
+    Block(usize),
+    /// One past the last instruction: a clean halt
+    Exit,
+    /// Anywhere else; executing this edge is a runtime error
+    Invalid(isize),
+    /// Decided at run time by an extension opcode's handler
+    Unknown,
+}
+
+/// A maximal run of instructions that is only entered at `start` and only left after `end - 1`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BasicBlock {
+    pub start: usize,
+    /// Exclusive
+    pub end: usize,
+    pub successors: Vec<Target>,
+}
+
+/// A `jmp` that lands outside the program
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct InvalidJump {
+    pub ip: usize,
+    pub target: isize,
+}
+
+/// Static control-flow analysis of a program.  Extension opcodes end their block with an
+/// `Unknown` successor, so once one is reachable every block is treated as reachable.
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph {
+    pub blocks: Vec<BasicBlock>,
+    /// Block index of every instruction
+    pub block_of: Vec<usize>,
+    /// Per block, whether it can run starting from instruction 0
+    pub reachable: Vec<bool>,
+    pub invalid_jumps: Vec<InvalidJump>,
+}
+
+fn jump_target(ip: usize, offset: isize) -> isize {
+    isize::try_from(ip).map(|ip| ip.saturating_add(offset)).unwrap_or(isize::MAX)
+}
+
+/// Label for each jump destination, named in program order; the exit is `end`
+pub fn jump_labels(program: &[Instruction]) -> BTreeMap<usize, String> {
+    let targets: BTreeSet<usize> = program.iter().enumerate()
+        .filter_map(|(ip, instr)| match instr {
+            Instruction::Jmp(offset) => usize::try_from(jump_target(ip, *offset)).ok().filter(|target| *target <= program.len()),
+            _ => None,
+        })
+        .collect();
+    targets.into_iter().enumerate()
+        .map(|(idx, ip)| (ip, if ip == program.len() { "end".to_string() } else { format!("L{}", idx) }))
+        .collect()
+}
+
+impl ControlFlowGraph {
+    pub fn build(program: &[Instruction]) -> ControlFlowGraph {
+        let len = program.len();
+        let in_range = |target: isize| usize::try_from(target).ok().filter(|target| *target <= len);
+
+        // block leaders: the entry point, every jump target and whatever follows a jump or an
+        // extension opcode
+        let mut leaders = BTreeSet::new();
+        leaders.insert(0);
+        let mut invalid_jumps = Vec::new();
+        for (ip, instr) in program.iter().enumerate() {
+            if let Instruction::Jmp(offset) = instr {
+                let target = jump_target(ip, *offset);
+                match in_range(target) {
+                    Some(target) => { leaders.insert(target); },
+                    None => invalid_jumps.push(InvalidJump { ip, target }),
+                }
+                leaders.insert(ip + 1);
+            } else if let Instruction::Ext(..) = instr {
+                leaders.insert(ip + 1);
+            }
+        }
+        let starts: Vec<usize> = leaders.into_iter().filter(|start| *start < len).collect();
+
+        let mut block_of = vec![0; len];
+        let mut blocks = Vec::with_capacity(starts.len());
+        for (idx, start) in starts.iter().enumerate() {
+            let end = starts.get(idx + 1).copied().unwrap_or(len);
+            for owner in block_of[*start .. end].iter_mut() {
+                *owner = idx;
+            }
+            blocks.push(BasicBlock { start: *start, end, successors: Vec::new() });
+        }
+        let target_of = |ip: usize, block_of: &[usize]| if ip == len { Target::Exit } else { Target::Block(block_of[ip]) };
+        for block in blocks.iter_mut() {
+            let last = block.end - 1;
+            block.successors = match &program[last] {
+                Instruction::Jmp(offset) => {
+                    let target = jump_target(last, *offset);
+                    vec![in_range(target).map(|ip| target_of(ip, &block_of)).unwrap_or(Target::Invalid(target))]
+                },
+                Instruction::Ext(..) => vec![Target::Unknown],
+                _ => vec![target_of(block.end, &block_of)],
+            };
+        }
+
+        let mut reachable = vec![false; blocks.len()];
+        let mut queue = Vec::new();
+        if !blocks.is_empty() {
+            reachable[0] = true;
+            queue.push(0);
+        }
+        while let Some(idx) = queue.pop() {
+            for successor in blocks[idx].successors.iter() {
+                match successor {
+                    Target::Block(next) if !reachable[*next] => {
+                        reachable[*next] = true;
+                        queue.push(*next);
+                    },
+                    Target::Unknown => {
+                        reachable.iter_mut().for_each(|reachable| *reachable = true);
+                        queue.clear();
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        ControlFlowGraph { blocks, block_of, reachable, invalid_jumps }
+    }
+
+    /// Instructions that can't run starting from instruction 0
+    pub fn unreachable_instructions(&self) -> Vec<usize> {
+        self.blocks.iter().zip(self.reachable.iter())
+            .filter(|(_, reachable)| !**reachable)
+            .flat_map(|(block, _)| block.start .. block.end)
+            .collect()
+    }
+
+    /// Listing with jump targets replaced by labels, each block introduced by a comment and
+    /// problems flagged inline.  Comments start with `;`.
+    pub fn disassemble(&self, program: &[Instruction]) -> String {
+        let labels = jump_labels(program);
+        let mut out = String::new();
+        for (idx, block) in self.blocks.iter().enumerate() {
+            let reachability = if self.reachable[idx] { "" } else { ", unreachable" };
+            writeln!(out, "; block {} ({}..{}{})", idx, block.start, block.end, reachability).unwrap();
+            if let Some(label) = labels.get(&block.start) {
+                writeln!(out, "{}:", label).unwrap();
+            }
+            for (ip, instr) in program.iter().enumerate().take(block.end).skip(block.start) {
+                let text = match instr {
+                    Instruction::Jmp(offset) => {
+                        let target = jump_target(ip, *offset);
+                        match usize::try_from(target).ok().and_then(|target| labels.get(&target)) {
+                            Some(label) => format!("jmp {}", label),
+                            None => format!("jmp {:+}", offset),
+                        }
+                    },
+                    instr => instr.to_string(),
+                };
+                let note = match self.invalid_jumps.iter().find(|jump| jump.ip == ip) {
+                    Some(jump) => format!("; {} - jumps outside the program to {}", ip, jump.target),
+                    None => format!("; {}", ip),
+                };
+                writeln!(out, "    {:<16}{}", text, note).unwrap();
+            }
+        }
+        if let Some(label) = labels.get(&program.len()) {
+            writeln!(out, "{}:", label).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::{Flow, OpcodeTable, Registers};
+
+    fn skip(_: &mut Registers, _: isize) -> Result<Flow, String> {
+        Ok(Flow::Jump(2))
+    }
+
+    #[test]
+    fn code_after_a_jump_is_unreachable() {
+        let program = vec![Instruction::Acc(1), Instruction::Jmp(2), Instruction::Acc(5), Instruction::Nop(0)];
+        let cfg = ControlFlowGraph::build(&program);
+        assert_eq!(cfg.blocks.len(), 3);
+        assert_eq!(cfg.blocks[0].successors, vec![Target::Block(2)]);
+        assert_eq!(cfg.blocks[2].successors, vec![Target::Exit]);
+        assert_eq!(cfg.unreachable_instructions(), vec![2]);
+    }
+
+    #[test]
+    fn extension_opcodes_have_unknown_successors() {
+        let mut opcodes = OpcodeTable::new();
+        opcodes.register("skip", skip).unwrap();
+        let program = vec![opcodes.parse("skip +0").unwrap(), Instruction::Jmp(2), Instruction::Acc(5), Instruction::Nop(0)];
+        let cfg = ControlFlowGraph::build(&program);
+        assert_eq!(cfg.blocks[0].successors, vec![Target::Unknown]);
+        assert!(cfg.unreachable_instructions().is_empty());
+        // an extension opcode that can't run changes nothing
+        let program = vec![Instruction::Jmp(2), opcodes.parse("skip +0").unwrap(), Instruction::Acc(5)];
+        assert_eq!(ControlFlowGraph::build(&program).unreachable_instructions(), vec![1]);
+    }
+}
@@ -0,0 +1,312 @@
+use super::{Instruction, Machine, StepOutcome};
+use anyhow::{anyhow, bail, Result};
+use bit_vec::BitVec;
+use std::io::{BufRead, Write};
+
+/// How many instructions either side of the pointer `list` shows by default
+const WINDOW: usize = 3;
+
+const HELP: &str = "\
+commands:
+  s, step [N]              execute N instructions (default 1)
+  c, continue              run until a breakpoint, halt, error or loop
+  b, break IP              break before executing IP
+  b, break acc OP N        break when acc OP N becomes true (OP: == != < <= > >=)
+  d, delete N              remove breakpoint N
+  i, info                  list breakpoints and watches
+  w, watch acc             report every change to acc
+  unwatch acc              stop watching acc
+  l, list [N]              show N instructions around the pointer
+  p, print                 show ip and acc
+  patch IP INSTRUCTION     replace the instruction at IP, e.g. `patch 7 nop +3`
+  r, reset                 restart from the first instruction
+  h, help                  show this message
+  q, quit                  leave the debugger";
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    fn parse(op: &str) -> Result<Comparison> {
+        Ok(match op {
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<" => Comparison::Lt,
+            "<=" => Comparison::Le,
+            ">" => Comparison::Gt,
+            ">=" => Comparison::Ge,
+            _ => bail!("Unknown comparison: {}", op),
+        })
+    }
+
+    fn holds(&self, l: i128, r: i128) -> bool {
+        match self {
+            Comparison::Eq => l == r,
+            Comparison::Ne => l != r,
+            Comparison::Lt => l < r,
+            Comparison::Le => l <= r,
+            Comparison::Gt => l > r,
+            Comparison::Ge => l >= r,
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Breakpoint {
+    /// Stop before executing this instruction
+    Ip(usize),
+    /// Stop when a step makes the accumulator satisfy the comparison
+    Acc(Comparison, i128),
+}
+
+impl Breakpoint {
+    fn hit(&self, machine: &Machine, acc_before: i128) -> bool {
+        match self {
+            Breakpoint::Ip(ip) => machine.ip() == *ip,
+            Breakpoint::Acc(cmp, value) => cmp.holds(machine.acc(), *value) && !cmp.holds(acc_before, *value),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Breakpoint::Ip(ip) => format!("ip {}", ip),
+            Breakpoint::Acc(cmp, value) => format!("acc {} {}", cmp.symbol(), value),
+        }
+    }
+}
+
+/// Why a `step`/`continue` stopped
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StopReason {
+    Stepped,
+    Breakpoint(usize),
+    Halted,
+    /// Continuing would execute an instruction already executed since the last stop
+    Loop,
+    Error(String),
+}
+
+/// Drives a `Machine` one command at a time.  Commands can come from any reader so the same
+/// debugger works interactively or scripted.
+pub struct Debugger {
+    machine: Machine,
+    breakpoints: Vec<Breakpoint>,
+    watch_acc: bool,
+}
+
+impl Debugger {
+    pub fn new(machine: Machine) -> Debugger {
+        Debugger { machine, breakpoints: Vec::new(), watch_acc: false }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(breakpoint);
+        self.breakpoints.len() - 1
+    }
+
+    pub fn set_watch_acc(&mut self, watch: bool) {
+        self.watch_acc = watch;
+    }
+
+    /// Replaces the instruction at `ip`; execution state is left as is
+    pub fn patch(&mut self, ip: usize, instruction: Instruction) -> Result<Instruction> {
+        let slot = self.machine.program_mut().get_mut(ip).ok_or_else(|| anyhow!("No instruction at {}", ip))?;
+        Ok(std::mem::replace(slot, instruction))
+    }
+
+    pub fn reset(&mut self) {
+        self.machine.reset();
+    }
+
+    /// Executes up to `count` instructions, stopping early on halts and errors.  Watch output
+    /// goes to `out`.
+    pub fn step<W: Write>(&mut self, count: usize, out: &mut W) -> Result<StopReason> {
+        for _ in 0 .. count {
+            match self.single_step(out)? {
+                StopReason::Stepped => {},
+                reason => return Ok(reason),
+            }
+        }
+        Ok(StopReason::Stepped)
+    }
+
+    /// Runs until a breakpoint is hit, the program halts or fails, or an instruction repeats
+    pub fn resume<W: Write>(&mut self, out: &mut W) -> Result<StopReason> {
+        let mut visited = BitVec::from_elem(self.machine.program().len(), false);
+        loop {
+            if let Some(true) = visited.get(self.machine.ip()) {
+                return Ok(StopReason::Loop);
+            }
+            if self.machine.ip() < visited.len() {
+                visited.set(self.machine.ip(), true);
+            }
+            let acc_before = self.machine.acc();
+            match self.single_step(out)? {
+                StopReason::Stepped => {},
+                reason => return Ok(reason),
+            }
+            if let Some(idx) = self.breakpoints.iter().position(|bp| bp.hit(&self.machine, acc_before)) {
+                return Ok(StopReason::Breakpoint(idx));
+            }
+        }
+    }
+
+    fn single_step<W: Write>(&mut self, out: &mut W) -> Result<StopReason> {
+        let (ip, acc_before) = (self.machine.ip(), self.machine.acc());
+        match self.machine.step() {
+            Ok(outcome) => {
+                if self.watch_acc && self.machine.acc() != acc_before {
+                    writeln!(out, "watch: acc {} -> {} (at {})", acc_before, self.machine.acc(), ip)?;
+                }
+                Ok(match outcome {
+                    StepOutcome::Running => StopReason::Stepped,
+                    StepOutcome::Halted => StopReason::Halted,
+                })
+            },
+            Err(err) => Ok(StopReason::Error(err.to_string())),
+        }
+    }
+
+    /// The instructions within `radius` of the pointer, with the current one marked
+    pub fn listing(&self, radius: usize) -> String {
+        let program = self.machine.program();
+        let ip = self.machine.ip();
+        let start = ip.saturating_sub(radius);
+        let end = ip.saturating_add(radius).saturating_add(1).min(program.len());
+        let mut listing = String::new();
+        for (idx, instr) in program.iter().enumerate().take(end).skip(start) {
+            let marker = if idx == ip { "=>" } else { "  " };
+            let bp = if self.breakpoints.contains(&Breakpoint::Ip(idx)) { "*" } else { " " };
+            listing.push_str(&format!("{}{}{:>5}: {}\n", marker, bp, idx, instr));
+        }
+        if ip >= program.len() {
+            listing.push_str(&format!("=> {:>5}: <end of program>\n", ip));
+        }
+        listing
+    }
+
+    fn report<W: Write>(&self, reason: &StopReason, out: &mut W) -> Result<()> {
+        match reason {
+            StopReason::Stepped => {},
+            StopReason::Breakpoint(idx) => writeln!(out, "breakpoint {} hit ({})", idx, self.breakpoints[*idx].describe())?,
+            StopReason::Halted => writeln!(out, "program halted with acc {}", self.machine.acc())?,
+            StopReason::Loop => writeln!(out, "stopped: instruction {} would run a second time", self.machine.ip())?,
+            StopReason::Error(message) => writeln!(out, "execution error: {}", message)?,
+        }
+        write!(out, "{}", self.listing(1))?;
+        Ok(())
+    }
+
+    /// Runs one command; returns `false` once the user asks to quit
+    pub fn execute_command<W: Write>(&mut self, line: &str, out: &mut W) -> Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {},
+            ["q"] | ["quit"] => return Ok(false),
+            ["h"] | ["help"] => writeln!(out, "{}", HELP)?,
+            ["s"] | ["step"] => {
+                let reason = self.step(1, out)?;
+                self.report(&reason, out)?;
+            },
+            ["s", count] | ["step", count] => {
+                let reason = self.step(count.parse()?, out)?;
+                self.report(&reason, out)?;
+            },
+            ["c"] | ["continue"] => {
+                let reason = self.resume(out)?;
+                self.report(&reason, out)?;
+            },
+            ["b", "acc", op, value] | ["break", "acc", op, value] => {
+                let idx = self.add_breakpoint(Breakpoint::Acc(Comparison::parse(op)?, value.parse()?));
+                writeln!(out, "breakpoint {} set", idx)?;
+            },
+            ["b", ip] | ["break", ip] => {
+                let idx = self.add_breakpoint(Breakpoint::Ip(ip.parse()?));
+                writeln!(out, "breakpoint {} set", idx)?;
+            },
+            ["d", idx] | ["delete", idx] => {
+                let idx: usize = idx.parse()?;
+                if idx >= self.breakpoints.len() {
+                    bail!("No breakpoint {}", idx);
+                }
+                self.breakpoints.remove(idx);
+            },
+            ["i"] | ["info"] => {
+                for (idx, bp) in self.breakpoints.iter().enumerate() {
+                    writeln!(out, "breakpoint {}: {}", idx, bp.describe())?;
+                }
+                writeln!(out, "watching acc: {}", self.watch_acc)?;
+            },
+            ["w", "acc"] | ["watch", "acc"] => self.set_watch_acc(true),
+            ["unwatch", "acc"] => self.set_watch_acc(false),
+            ["l"] | ["list"] => write!(out, "{}", self.listing(WINDOW))?,
+            ["l", radius] | ["list", radius] => write!(out, "{}", self.listing(radius.parse()?))?,
+            ["p"] | ["print"] => writeln!(out, "ip {} acc {}", self.machine.ip(), self.machine.acc())?,
+            ["patch", ip, ..] => {
+                let ip: usize = ip.parse()?;
+                let instruction = self.machine.opcodes().parse(&words[2 ..].join(" "))?;
+                let old = self.patch(ip, instruction)?;
+                writeln!(out, "patched {}: {} -> {}", ip, old, self.machine.program()[ip])?;
+            },
+            ["r"] | ["reset"] => {
+                self.reset();
+                write!(out, "{}", self.listing(1))?;
+            },
+            _ => bail!("Unknown command: {} (try `help`)", line.trim()),
+        }
+        Ok(true)
+    }
+
+    /// Reads commands until `quit` or end of input.  Bad commands are reported and skipped.
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> Result<()> {
+        write!(out, "{}(dbg) ", self.listing(1))?;
+        out.flush()?;
+        for line in input.lines() {
+            match self.execute_command(&line?, out) {
+                Ok(true) => {},
+                Ok(false) => return Ok(()),
+                Err(err) => writeln!(out, "error: {}", err)?,
+            }
+            write!(out, "(dbg) ")?;
+            out.flush()?;
+        }
+        writeln!(out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_with_a_huge_radius_shows_the_whole_program() {
+        let mut debugger = Debugger::new(Machine::new(vec![Instruction::Nop(0), Instruction::Acc(1), Instruction::Jmp(-2)]));
+        debugger.step(1, &mut Vec::new()).unwrap();
+        let listing = debugger.listing(usize::MAX);
+        assert_eq!(listing.lines().count(), 3);
+        assert!(listing.lines().nth(1).unwrap().starts_with("=>"));
+    }
+}
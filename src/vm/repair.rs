@@ -0,0 +1,169 @@
+use super::Instruction;
+use anyhow::{bail, Result};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+
+/// A single `nop`/`jmp` swap that makes the program terminate
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Repair {
+    pub ip: usize,
+    pub original: Instruction,
+    pub replacement: Instruction,
+    /// Accumulator once the repaired program halts, computed without any overflow bound
+    pub acc: i128,
+}
+
+/// Where control goes after `instr` at `ip`, if that's inside the program or exactly one past
+/// the end.  Jumps anywhere else can never lead to a clean exit.
+fn next_ip(ip: usize, instr: &Instruction, len: usize) -> Result<Option<usize>> {
+    let target = match instr {
+        Instruction::Nop(_) | Instruction::Acc(_) => Some(ip + 1),
+        Instruction::Jmp(offset) => isize::try_from(ip).ok()
+            .and_then(|ip| ip.checked_add(*offset))
+            .and_then(|target| usize::try_from(target).ok()),
+        Instruction::Ext(_, _) => bail!("Can't analyze control flow through extension opcode at {}", ip),
+    };
+    Ok(target.filter(|target| *target <= len))
+}
+
+fn swapped(instr: &Instruction) -> Option<Instruction> {
+    match instr {
+        Instruction::Nop(offset) => Some(Instruction::Jmp(*offset)),
+        Instruction::Jmp(offset) => Some(Instruction::Nop(*offset)),
+        _ => None,
+    }
+}
+
+fn acc_delta(instr: &Instruction) -> i128 {
+    match instr {
+        Instruction::Acc(value) => i128::from(*value),
+        _ => 0,
+    }
+}
+
+/// For every instruction (plus the exit at `len`), how much the accumulator grows on the way to
+/// a clean exit when the unmodified program runs from there, or `None` if it never exits.
+/// Every instruction has a single successor, so the instructions that exit form a tree rooted
+/// at `len`; walking it backwards keeps this linear in the program size.
+pub fn exit_accumulators(program: &[Instruction]) -> Result<Vec<Option<i128>>> {
+    let len = program.len();
+    let mut incoming: Vec<Vec<usize>> = vec![Vec::new(); len + 1];
+    for (ip, instr) in program.iter().enumerate() {
+        if let Some(target) = next_ip(ip, instr, len)? {
+            incoming[target].push(ip);
+        }
+    }
+    let mut exit_acc = vec![None; len + 1];
+    exit_acc[len] = Some(0);
+    let mut queue = VecDeque::new();
+    queue.push_back(len);
+    while let Some(ip) = queue.pop_front() {
+        let acc = exit_acc[ip].expect("queued instructions exit");
+        for source in incoming[ip].iter() {
+            if exit_acc[*source].is_none() {
+                exit_acc[*source] = Some(acc + acc_delta(&program[*source]));
+                queue.push_back(*source);
+            }
+        }
+    }
+    Ok(exit_acc)
+}
+
+/// Every single-instruction swap that makes a looping program halt, found in linear time.
+/// Only instructions the original run actually reaches can matter; swapping one of those
+/// helps exactly when its new target already leads to the exit.  Returns nothing if the
+/// program already terminates.
+pub fn find_repairs(program: &[Instruction]) -> Result<Vec<Repair>> {
+    let len = program.len();
+    let exit_acc = exit_accumulators(program)?;
+    if exit_acc[0].is_some() {
+        return Ok(Vec::new());
+    }
+
+    let mut reached = vec![false; len];
+    let mut ip = 0;
+    let mut acc = 0;
+    let mut repairs = Vec::new();
+    while ip < len && !reached[ip] {
+        reached[ip] = true;
+        let instr = &program[ip];
+        if let Some(replacement) = swapped(instr) {
+            // swapped instructions are nop/jmp, so the accumulator is unchanged by them
+            if let Some(remaining) = next_ip(ip, &replacement, len)?.and_then(|target| exit_acc[target]) {
+                repairs.push(Repair { ip, original: instr.clone(), replacement, acc: acc + remaining });
+            }
+        }
+        acc += acc_delta(instr);
+        match next_ip(ip, instr, len)? {
+            Some(next) => ip = next,
+            None => break,
+        }
+    }
+    Ok(repairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::execute;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    /// Flips every nop/jmp in turn and keeps the ones whose run halts
+    fn flip_and_execute(program: &[Instruction]) -> Vec<(usize, i128)> {
+        let mut repairs = Vec::new();
+        for (ip, instr) in program.iter().enumerate() {
+            if let Some(replacement) = swapped(instr) {
+                let mut patched = program.to_vec();
+                patched[ip] = replacement;
+                if let Ok(acc) = execute(&patched) {
+                    repairs.push((ip, acc));
+                }
+            }
+        }
+        repairs
+    }
+
+    #[test]
+    fn repairs_puzzle_example() {
+        let program = vec![
+            Instruction::Nop(0), Instruction::Acc(1), Instruction::Jmp(4), Instruction::Acc(3), Instruction::Jmp(-3),
+            Instruction::Acc(-99), Instruction::Acc(1), Instruction::Jmp(-4), Instruction::Acc(6),
+        ];
+        let repairs = find_repairs(&program).unwrap();
+        assert_eq!(repairs, vec![Repair { ip: 7, original: Instruction::Jmp(-4), replacement: Instruction::Nop(-4), acc: 8 }]);
+    }
+
+    #[test]
+    fn finds_every_valid_repair() {
+        let program = vec![Instruction::Acc(1), Instruction::Nop(3), Instruction::Acc(2), Instruction::Jmp(-1)];
+        let repairs: Vec<(usize, i128)> = find_repairs(&program).unwrap().iter().map(|repair| (repair.ip, repair.acc)).collect();
+        assert_eq!(repairs, vec![(1, 1), (3, 3)]);
+    }
+
+    #[test]
+    fn terminating_program_needs_no_repair() {
+        let program = vec![Instruction::Acc(1), Instruction::Jmp(2), Instruction::Jmp(-2), Instruction::Nop(0)];
+        assert!(find_repairs(&program).unwrap().is_empty());
+    }
+
+    #[test]
+    fn random_programs_match_flip_and_execute() {
+        let mut rng = StdRng::seed_from_u64(2020);
+        for _ in 0 .. 2000 {
+            let len = rng.gen_range(1 ..= 10);
+            let program: Vec<Instruction> = (0 .. len)
+                .map(|_| match rng.gen_range(0 .. 3) {
+                    0 => Instruction::Nop(rng.gen_range(-4 ..= 4)),
+                    1 => Instruction::Acc(rng.gen_range(-9 ..= 9)),
+                    _ => Instruction::Jmp(rng.gen_range(-4 ..= 4)),
+                })
+                .collect();
+            // repairs come back in execution order
+            let mut found: Vec<(usize, i128)> = find_repairs(&program).unwrap().iter().map(|repair| (repair.ip, repair.acc)).collect();
+            found.sort_unstable();
+            let expected = if execute(&program).is_ok() { Vec::new() } else { flip_and_execute(&program) };
+            assert_eq!(found, expected, "program: {:?}", program);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use super::{ExecutionError, Instruction, Machine};
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use thiserror::Error;
+
+/// One executed instruction and its effect on the accumulator
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TraceStep {
+    pub ip: usize,
+    pub instruction: Instruction,
+    pub acc_before: i128,
+    pub acc_after: i128,
+}
+
+/// Steps recorded by a `Machine` with tracing enabled, in execution order
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+}
+
+#[derive(Error, Debug)]
+pub enum ReplayError {
+    #[error("step {step} diverged from the recorded trace (recorded: {recorded:?}, replayed: {replayed:?})")]
+    Diverged {
+        step: usize,
+        recorded: Box<TraceStep>,
+        replayed: Option<Box<TraceStep>>,
+    },
+    #[error("replay failed at step {step}")]
+    Execution {
+        step: usize,
+        source: ExecutionError,
+    },
+}
+
+impl Trace {
+    /// One line per step: `<step> <ip>: <instruction>  acc <before> -> <after>`
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for (idx, step) in self.steps.iter().enumerate() {
+            writeln!(out, "{:>6} {:>5}: {:<10} acc {} -> {}", idx, step.ip, step.instruction.to_string(), step.acc_before, step.acc_after).unwrap();
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Trace> {
+        serde_json::from_str(json)
+    }
+
+    /// Resets `machine` and re-runs it for as many steps as were recorded, checking that every
+    /// step matches the trace.  Returns the final accumulator; the machine is left tracing.
+    pub fn replay(&self, machine: &mut Machine) -> Result<i128, ReplayError> {
+        machine.reset();
+        machine.enable_trace();
+        for (step, recorded) in self.steps.iter().enumerate() {
+            machine.step().map_err(|source| ReplayError::Execution { step, source })?;
+            // a halted machine records nothing further, which shows up as a missing step
+            let replayed = machine.trace().and_then(|trace| trace.steps.get(step));
+            if replayed != Some(recorded) {
+                return Err(ReplayError::Diverged { step, recorded: Box::new(recorded.clone()), replayed: replayed.cloned().map(Box::new) });
+            }
+        }
+        Ok(machine.acc())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recorded(program: Vec<Instruction>) -> Trace {
+        let mut machine = Machine::new(program);
+        machine.enable_trace();
+        machine.run().unwrap();
+        machine.take_trace().unwrap()
+    }
+
+    #[test]
+    fn replay_matches_a_recorded_run() {
+        let program = vec![Instruction::Acc(4), Instruction::Jmp(2), Instruction::Acc(100), Instruction::Acc(-1)];
+        let trace = recorded(program.clone());
+        assert_eq!(trace.steps.iter().map(|step| step.ip).collect::<Vec<_>>(), vec![0, 1, 3]);
+        let trace = Trace::from_json(&trace.to_json().unwrap()).unwrap();
+        let mut machine = Machine::new(program);
+        assert_eq!(trace.replay(&mut machine).unwrap(), 3);
+    }
+
+    #[test]
+    fn replay_reports_divergence() {
+        let trace = recorded(vec![Instruction::Acc(4), Instruction::Jmp(2), Instruction::Acc(100), Instruction::Acc(-1)]);
+        let mut machine = Machine::new(vec![Instruction::Acc(4), Instruction::Nop(2), Instruction::Acc(100), Instruction::Acc(-1)]);
+        match trace.replay(&mut machine) {
+            Err(ReplayError::Diverged { step: 1, recorded, replayed: Some(replayed) }) => {
+                assert_eq!(recorded.instruction, Instruction::Jmp(2));
+                assert_eq!(replayed.instruction, Instruction::Nop(2));
+            },
+            other => panic!("expected a divergence, got {:?}", other),
+        }
+        // a program that halts early leaves the rest of the trace unmatched
+        let mut machine = Machine::new(vec![Instruction::Acc(4)]);
+        assert!(matches!(trace.replay(&mut machine), Err(ReplayError::Diverged { step: 1, replayed: None, .. })));
+    }
+}